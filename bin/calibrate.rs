@@ -1,12 +1,19 @@
-use arrayvec::ArrayVec;
 use clap::{Error, ErrorKind};
-use compute::prelude::{argmin, interp1d_linear_unchecked, linspace, ExtrapolationMode, Vector};
-use dragonfly::{calibration::{
-        FTAction, FTCommand, FrameData, MODEL_FLUX, MODEL_FLUX_NII, MODEL_TILT,
-    }, sextractor::{CatalogObject, run_sextractor}, utils::round_to_digits};
-use rayon::prelude::*;
+use dragonfly::{
+    calibration::{
+        bootstrap_uncertainty, check_fit_data, fill_errors, fit_filter_calibration,
+        fit_tilt_calibration, jacobian_uncertainty, journal_path, new_run_id, normalize_flux,
+        read_journal, uniform_angles, write_chain_csv, AdaptiveSweep, ChainSummary, Combine,
+        DarkLibrary, Filter, FilterRegistry, FilterSpec, FilterTilter, FrameData, FreeParameters,
+        InterpolatedTransmission, RunJournal, SimulatedRig, SpotFinder, SweepConfig,
+        SyntheticFrame, TiltFit, TiltPosterior, TiltPriors, TransmissionCurves, TransmissionModel,
+        TravelLimits, Wavefront, CONFIDENCE_LEVEL,
+    },
+    error::Error as DFError,
+    sextractor::run_sextractor,
+};
 
-use std::{env, fs::remove_file, process::Command, thread::current};
+use std::{env, fs::remove_file, process::Command, thread::current, time::Duration};
 use structopt::{
     clap::AppSettings::{ColorAuto, ColoredHelp},
     StructOpt,
@@ -16,193 +23,446 @@ use structopt::{
 #[structopt(
     name = "Dragonfly: Calibration",
     about = "Calibrates a filter-tilter unit.",
-    author,
+    author
 )]
 #[structopt(setting(ColorAuto), setting(ColoredHelp))]
 struct Opt {
     /// USB serial port number
     #[structopt(long, default_value = "COM5", name = "port_name")]
     port: String,
-    /// Degrees of tilt to start the calibration at. Must be in [160., 200.] and less than the
-    /// end angle `end`.
+    /// Degrees of tilt to start the calibration at. Must be within the travel limits and less
+    /// than the end angle `end`.
     #[structopt(long, default_value = "160.", name = "start_angle")]
     start: f64,
-    /// Degrees of tilt to end the calibration at. Must be in [160., 200.] and greater than the
-    /// start angle `start`.
+    /// Degrees of tilt to end the calibration at. Must be within the travel limits and greater
+    /// than the start angle `start`.
     #[structopt(long, default_value = "200.", name = "end_angle")]
     end: f64,
+    /// Lowest raw angle this unit can safely be driven to.
+    #[structopt(long, default_value = "160.", name = "min_degrees")]
+    min_angle: f64,
+    /// Highest raw angle this unit can safely be driven to.
+    #[structopt(long, default_value = "200.", name = "max_degrees")]
+    max_angle: f64,
     /// Number of steps to take between the start and end angle (i.e., larger value means a higher
-    /// resolution). Must be at least 2.
+    /// resolution). Must be at least 2. With `--adaptive`, the number of steps in the coarse pass.
     #[structopt(long, default_value = "30")]
     nstep: usize,
     /// Time in seconds for each exposure.
     #[structopt(long, default_value = "60.", name = "exposure_seconds")]
     exptime: f64,
-    /// Whether to run in simulation mode (expose, but don't tilt).
+    /// Whether to run in simulation mode (simulate both the filter-tilter and the laser spot
+    /// using the filter transmission model).
     #[structopt(short, long)]
     simulation: bool,
+    /// In simulation mode, the hidden tilt in degrees of the filter at a raw angle of 180.
+    #[structopt(long, default_value = "0.", name = "sim_offset_degrees")]
+    sim_offset: f64,
+    /// In simulation mode, the strength of the [NII] line relative to Hα.
+    #[structopt(long, default_value = "0.3", name = "sim_nii_fraction")]
+    sim_nii_fraction: f64,
+    /// In simulation mode, the fractional noise on each simulated spot flux.
+    #[structopt(long, default_value = "0.01", name = "sim_noise")]
+    sim_noise: f64,
+    /// In simulation mode, render synthetic FITS frames and run SExtractor on them instead of
+    /// using the simulated spot fluxes directly.
+    #[structopt(long)]
+    sim_images: bool,
+    /// After a coarse pass of `nstep` angles, keep adding angles on the edges of the
+    /// transmission curve, where the flux is most sensitive to the tilt shift, until the tilt
+    /// shift is known to `target_degrees`.
+    #[structopt(long)]
+    adaptive: bool,
+    /// Standard error of the tilt shift, in degrees, at which an adaptive sweep stops.
+    #[structopt(long, default_value = "0.02", name = "target_degrees")]
+    target_uncertainty: f64,
+    /// Most angles an adaptive sweep measures in total, including the coarse pass.
+    #[structopt(long, default_value = "60")]
+    max_steps: usize,
+    /// Number of angles an adaptive sweep adds between fits.
+    #[structopt(long, default_value = "4")]
+    refine_batch: usize,
     /// Number of frames to average over at each tilt angle.
     #[structopt(long, default_value = "1")]
     naverage: usize,
+    /// How to combine the frames at each angle: `mean`, `median` or `sigma-clip` (a mean after
+    /// rejecting outliers, such as a cosmic ray picked as the spot).
+    #[structopt(long, default_value = "sigma-clip")]
+    combine: Combine,
+    /// Subtract a master dark from every frame before extracting the spot. The master dark is
+    /// the median of `ndarks` darks at the exposure time, and is reused from `dark_dir` while the
+    /// exposure time and sensor temperature match.
+    #[structopt(long, conflicts_with = "simulation")]
+    darks: bool,
+    /// Number of darks to combine into a master dark.
+    #[structopt(long, default_value = "5")]
+    ndarks: usize,
+    /// Largest difference in sensor temperature, in degrees Celsius, at which a master dark is
+    /// reused.
+    #[structopt(long, default_value = "1.", name = "dark_tolerance_celsius")]
+    dark_tolerance: f64,
+    /// Directory master darks are kept in.
+    #[structopt(long, default_value = "darks")]
+    dark_dir: String,
     /// Whether to save the captured images.
     #[structopt(short, long)] // , requires = "tempdir")]
     keep: bool,
     // /// Location to save the captured images.
     // #[structopt(long)]
     // tempdir: Option<PathBuf>,
+    /// How close in degrees the filter must get to each requested angle.
+    #[structopt(long, default_value = "0.05", name = "tolerance_degrees")]
+    tolerance: f64,
+    /// Time in seconds to wait for the filter to settle at each angle.
+    #[structopt(long, default_value = "30.", name = "settle_seconds")]
+    settle_timeout: f64,
+    /// Estimate fit uncertainties by refitting this many bootstrap resamples of the frames,
+    /// instead of from the Jacobian at the best fit.
+    #[structopt(long, name = "nresamples")]
+    bootstrap: Option<usize>,
+    /// Also sample the posterior of the calibration with an ensemble MCMC sampler, for this many
+    /// steps. The first half of the chain is discarded as burn-in in the summary.
+    #[structopt(long, name = "nsteps")]
+    mcmc: Option<usize>,
+    /// Number of walkers for the MCMC sampler.
+    #[structopt(long, default_value = "32")]
+    nwalkers: usize,
+    /// JSON file with per-unit priors for the MCMC sampler.
+    #[structopt(long, name = "priors_file")]
+    priors: Option<String>,
+    /// Write the MCMC chain to `<prefix>.csv` and its summary to `<prefix>.json`.
+    #[structopt(long, name = "prefix")]
+    mcmc_out: Option<String>,
+    /// Filter whose transmission curve the fit models, by its name in the filter directory
+    /// (`$DRAGONFLY_FILTER_DIR`), e.g. `3.1BPF_0deg`.
+    #[structopt(long, default_value = "3.1BPF_0deg")]
+    filter: Filter,
+    /// Wavefront illuminating the filter: `coll`, `3deg` or `22deg`.
+    #[structopt(long, default_value = "coll")]
+    wavefront: Wavefront,
+    /// Central wavelength of the filter at normal incidence in nm, if different from the filter's
+    /// nominal CWL.
+    #[structopt(long, name = "filter_cwl_nm")]
+    filter_cwl: Option<f64>,
+    /// Central wavelengths of the laser lines in nm, primary line first.
+    #[structopt(
+        long,
+        default_value = "656.3,658.5",
+        use_delimiter = true,
+        name = "laser_lines_nm"
+    )]
+    laser_lines: Vec<f64>,
+    /// FWHM of each laser line, in nm.
+    #[structopt(long, default_value = "0.61", name = "laser_fwhm_nm")]
+    laser_fwhm: f64,
+    /// Effective refractive index of the filter.
+    #[structopt(long, default_value = "2.1")]
+    effective_index: f64,
+    /// Bandwidth of the filter in nm, if different from the filter's nominal bandwidth.
+    #[structopt(long, name = "bandwidth_nm")]
+    filter_bandwidth: Option<f64>,
+    /// Also fit the filter CWL, instead of holding it at `filter_cwl_nm`.
+    #[structopt(long)]
+    fit_filter_cwl: bool,
+    /// Also fit the effective index, instead of holding it at `effective_index`.
+    #[structopt(long)]
+    fit_effective_index: bool,
+    /// Also fit the laser FWHM, instead of holding it at `laser_fwhm_nm`.
+    #[structopt(long)]
+    fit_laser_fwhm: bool,
+    /// Model the filter at intermediate tilts by interpolating between its measurements at two
+    /// tilts, instead of shifting the curve measured at normal incidence.
+    #[structopt(long)]
+    interpolate_tilt: bool,
+    /// Directory to cache model transmission curves in.
+    #[structopt(long, default_value = "cache")]
+    cache_dir: String,
+    /// Recompute the model transmission curves without reading or writing the cache.
+    #[structopt(long)]
+    no_cache: bool,
+    /// Directory run journals are kept in. Every measured frame is appended to
+    /// `<run_id>.jsonl` there as soon as it is taken.
+    #[structopt(long, default_value = "runs")]
+    journal_dir: String,
+    /// Resume an interrupted run, skipping the angles already recorded in its journal.
+    #[structopt(long, name = "run_id", conflicts_with = "refit_run_id")]
+    resume: Option<String>,
+    /// Skip data collection and refit the frames recorded in the journal of a previous run.
+    #[structopt(long, name = "refit_run_id")]
+    refit: Option<String>,
+    /// Number of times to retry a failed tilt or exposure before skipping it.
+    #[structopt(long, default_value = "2")]
+    retries: usize,
     /// Whether to be verbose and print messages.
     #[structopt(long, short = "v")]
     verbose: bool,
 }
 
-fn main() {
-    let opt = Opt::from_args();
-    if opt.start < 160. || opt.start > 200. {
-        Error::with_description(
-            "Start angle must be at least 160 and less than 200 degrees.",
-            ErrorKind::InvalidValue,
-        )
-        .exit()
-    }
-    if opt.end < 160. || opt.end > 200. {
-        Error::with_description(
-            "End angle must be at least 160 and less than 200 degrees.",
-            ErrorKind::InvalidValue,
-        )
-        .exit()
-    }
-    if opt.start > opt.end {
-        Error::with_description(
-            "Start angle must be less than end angle.",
-            ErrorKind::InvalidValue,
-        )
-        .exit()
-    }
-    if opt.nstep < 2 {
-        Error::with_description(
-            "Number of steps must be at least 2.",
-            ErrorKind::InvalidValue,
-        )
-        .exit()
-    }
-    if opt.exptime <= 0. {
-        Error::with_description("Exposure time must be positive.", ErrorKind::InvalidValue).exit()
+/// Take a single light frame at the current tilt, returning the path of the saved image.
+fn take_exposure(
+    opt: &Opt,
+    df_dir: &str,
+    current_angle: f64,
+    raw_angle: f64,
+) -> dragonfly::error::Result<String> {
+    let expose = Command::new("cscript")
+        .args(&[
+            "/nologo",
+            &format!("{}\\VBScript\\Expose.vbs", df_dir),
+            "light",
+            &format!("{}", opt.exptime),
+            &format!("/tiltgoal:{}", current_angle),
+            &format!("/rawtilt:{}", raw_angle),
+        ])
+        .output()
+        .map_err(|e| DFError::subprocess("cscript", e))?;
+
+    if !expose.status.success() {
+        return Err(DFError::subprocess(
+            "cscript",
+            String::from_utf8_lossy(&expose.stderr).trim(),
+        ));
     }
 
-    let df_dir = "/tmp";
+    let result = String::from_utf8_lossy(&expose.stdout).to_string();
 
-    // let df_dir = env::var("DFREPOSITORIES");
-    // if df_dir.is_err() {
-    //     Error::with_description(
-    //         "Could not find the DFREPOSITORIES environment variable!",
-    //         ErrorKind::EmptyValue,
-    //     )
-    //     .exit();
-    // }
-    // let df_dir = df_dir.unwrap();
+    // Expose.vbs reports "Saved <path>" on success.
+    match result.split_whitespace().nth(1) {
+        Some(filename) => Ok(filename.to_string()),
+        None => Err(DFError::subprocess(
+            "cscript",
+            format!("unexpected output from Expose.vbs: {:?}", result),
+        )),
+    }
+}
 
-    // let df_dir = format!("{}\\Dragonfly-MaximDL\\", df_dir);
+/// Spot area in pixels reported for simulated frames.
+const SIMULATED_SPOT_AREA: f64 = 100.;
+
+/// Sample the posterior of the calibration with MCMC starting from the best fit, printing its
+/// summary and writing the chain if asked to.
+fn sample_posterior(
+    opt: &Opt,
+    curves: &TransmissionCurves,
+    datatilt: &[f64],
+    dataflux: &[f64],
+    dataerr: &[f64],
+    best: &TiltFit,
+    nsteps: usize,
+) -> dragonfly::error::Result<()> {
+    let priors = match &opt.priors {
+        Some(path) => TiltPriors::from_file(path)?,
+        None => TiltPriors::default(),
+    };
+
+    let chain = TiltPosterior::new(curves, datatilt, dataflux, Some(dataerr), priors).sample(
+        best,
+        opt.nwalkers,
+        nsteps,
+    )?;
+    let summary = ChainSummary::new(&chain, nsteps / 2, 1);
+
+    for (name, p) in &summary.parameters {
+        println!(
+            "Posterior {}: median {} (16-84%: [{}, {}]), mean {} ± {}",
+            name, p.median, p.p16, p.p84, p.mean, p.std
+        );
+    }
+    println!(
+        "Mean acceptance fraction: {}",
+        summary.mean_acceptance_fraction
+    );
+
+    if let Some(prefix) = &opt.mcmc_out {
+        let written = write_chain_csv(&chain, &format!("{}.csv", prefix))
+            .and_then(|_| summary.write_json(&format!("{}.json", prefix)));
+        if let Err(e) = written {
+            println!("Could not write MCMC output: {}", e);
+        }
+    }
+    Ok(())
+}
 
-    let stepsize = (opt.end - opt.start) / (opt.nstep - 1) as f64;
-    let raw_angles = (0..opt.nstep)
-        .map(|x| round_to_digits(opt.start + x as f64 * stepsize, 1))
-        .collect::<Vector>();
+/// State carried from one angle of a sweep to the next.
+struct Session {
+    tilter: FilterTilter,
+    journal: RunJournal,
+    spots: SpotFinder,
+    darks: Option<DarkLibrary>,
+}
 
+/// Step through the raw angles, measuring the spot flux at each, and append the frames to
+/// `frames` and the run journal. Angles already in `frames` are not measured again.
+fn measure_angles(
+    opt: &Opt,
+    df_dir: &str,
+    raw_angles: &[f64],
+    session: &mut Session,
+    rig: &Option<SimulatedRig>,
+    frames: &mut Vec<FrameData>,
+) {
     if opt.verbose {
         println!("Raw angles: {:?}", raw_angles);
     }
 
-    let data = raw_angles
+    let measured = raw_angles
         .iter()
         .enumerate()
-        .map(|(i, current_angle)| {
-            
+        .filter_map(|(i, current_angle)| {
             if opt.verbose {
-                println!("Iteration {} of {}", i + 1, opt.nstep);
+                println!("Iteration {} of {}", i + 1, raw_angles.len());
             }
-            
-            let tilt_command = FTAction {
-                command: FTCommand::GET,
-                value: *current_angle,
-                portname: &opt.port,
-                simulation: Some(format!("{}/whatever-{}.txt", df_dir, alea::u32())),
-                verbose: true,
-            };
 
-            let tilt_result = tilt_command.run().expect("Filter tilter command failed!");
+            if frames.iter().any(|f| (f.angle - current_angle).abs() < 1e-9) {
+                if opt.verbose {
+                    println!("Angle {} is already in the journal, skipping it.", current_angle);
+                }
+                return None;
+            }
+
+            let raw_angle = match session.tilter.move_to(
+                *current_angle,
+                opt.tolerance,
+                Duration::from_secs_f64(opt.settle_timeout),
+            ) {
+                Ok(moved) => moved.raw_angle,
+                Err(e) => {
+                    println!("Skipping angle {}: {}", current_angle, e);
+                    return None;
+                }
+            };
 
             if opt.verbose {
-                println!("Tilt result: {:?} {}", tilt_result.0, tilt_result.1);
+                println!("Tilt result: {}", raw_angle);
             }
 
-            let raw_angle = tilt_result.1;
-
-            let mut area = 0.;
-            let mut flux = 0.;
-            let mut nobj = 0;
+            let mut fluxes = Vec::with_capacity(opt.naverage);
+            let mut areas = Vec::with_capacity(opt.naverage);
+            let mut nobjs = Vec::with_capacity(opt.naverage);
+            let mut spots = Vec::with_capacity(opt.naverage);
 
             (0..opt.naverage).for_each(|j| {
                 if opt.verbose {
                     println!("Taking image {} of {}", j + 1, opt.naverage);
                 }
 
-                let result = if opt.simulation {
-                    format!("Saved /home/js/programs/dragonfly/data/LaserCalibration/DRAGONFLY301_{}_light.fits", i + 1)
-                } else {
-                    let expose = Command::new("cscript")
-                        .args(&[
-                            "/nologo",
-                            &format!("{}\\VBScript\\Expose.vbs", df_dir),
-                            "light",
-                            &format!("{}", opt.exptime),
-                            &format!("/tiltgoal:{}", current_angle),
-                            &format!("/rawtilt:{}", raw_angle),
-                        ])
-                        .output()
-                        .expect("Could not run expose.vbs script!");
-
-                    String::from_utf8_lossy(&expose.stdout).to_string()
+                let exposure = match &rig {
+                    Some(rig) if !opt.sim_images => {
+                        let flux = match rig.measure_flux(raw_angle) {
+                            Ok(flux) => flux,
+                            Err(e) => {
+                                println!(
+                                    "Skipping image {} at angle {}: {}",
+                                    j + 1,
+                                    current_angle,
+                                    e
+                                );
+                                return;
+                            }
+                        };
+                        fluxes.push(flux);
+                        areas.push(SIMULATED_SPOT_AREA);
+                        nobjs.push(1);
+                        if opt.verbose {
+                            println!(
+                                "Iteration: {}\tAngle: {}\tSimulated SpotFlux: {}",
+                                j + 1,
+                                current_angle,
+                                flux
+                            );
+                        }
+                        return;
+                    }
+                    Some(rig) => {
+                        let path = format!("{}/simulated_{}_{}.fits", df_dir, i + 1, j + 1);
+                        Some(
+                            rig.measure_flux(raw_angle)
+                                .and_then(|flux| SyntheticFrame::default().write_fits(flux, &path))
+                                .map(|_| path),
+                        )
+                    }
+                    None => (0..=opt.retries)
+                        .map(|_| take_exposure(&opt, df_dir, *current_angle, raw_angle))
+                        .inspect(|res| {
+                            if let Err(e) = res {
+                                println!("Exposure failed: {}", e);
+                            }
+                        })
+                        .find(|res| res.is_ok()),
                 };
 
-                let fields = result.split_whitespace().collect::<ArrayVec<_, 2>>();
-
-                let filename = fields[1];
+                let filename = match exposure {
+                    Some(Ok(filename)) => filename,
+                    _ => {
+                        println!("Skipping image {} at angle {}.", j + 1, current_angle);
+                        return;
+                    }
+                };
 
                 if opt.verbose {
                     println!("Working on {}", filename);
-                    println!("Analyzing the image to select the object with the largest area.");
+                    println!("Analyzing the image to identify the laser spot.");
                 }
 
-                let catalog = run_sextractor(filename);
+                // Every frame from the camera would lack the temperature, so stop at the first
+                // rather than skipping the whole sweep frame by frame.
+                let catalog = match session.darks.as_mut() {
+                    Some(darks) => match darks.subtract(&filename, opt.exptime) {
+                        Err(e @ DFError::MissingTemperature(_)) => Error::with_description(
+                            &format!("{}. Run without --darks.", e),
+                            ErrorKind::InvalidValue,
+                        )
+                        .exit(),
+                        subtracted => subtracted.and_then(|subtracted| {
+                            let output = run_sextractor(&subtracted);
+                            if !opt.keep {
+                                if let Err(e) = remove_file(&subtracted) {
+                                    println!("Could not remove {}: {}", subtracted, e);
+                                }
+                            }
+                            output
+                        }),
+                    },
+                    None => run_sextractor(&filename),
+                };
 
                 match catalog {
-                    Ok(mut output) => {
-                        if !output.is_empty() {
-                            output.sort_by(|a, b| a.area.partial_cmp(&b.area).unwrap());
-                            area += output[0].area;
-                            flux += output[0].flux;
-                            nobj += output.len();
-                        } else {
-                            if opt.verbose {
-                                println!("No sources detected.");
+                    Ok(output) => {
+                        match session.spots.choose(&output) {
+                            Some(spot) => {
+                                if spot.ambiguous {
+                                    println!(
+                                        "Laser spot in {} is ambiguous, taking object {}.",
+                                        filename, spot.number
+                                    );
+                                }
+                                fluxes.push(spot.flux);
+                                areas.push(spot.area);
+                                spots.push(spot);
+                            }
+                            None => {
+                                fluxes.push(0.);
+                                areas.push(0.);
+                                if opt.verbose {
+                                    println!("No laser spot detected.");
+                                }
                             }
                         }
-                    },
-                    Err(_) => {
-                        if opt.verbose {
-                            println!("No sources detected.");
-                        }
+                        nobjs.push(output.len());
+                    }
+                    Err(e) => {
+                        println!("Skipping image {}: {}", filename, e);
                     }
                 }
 
-                if opt.verbose {
+                if opt.verbose && !fluxes.is_empty() {
                     println!(
                         "Iteration: {}\tAngle: {}\tNObj: {}\tSpotFlux: {}\tArea: {}",
                         j + 1,
                         current_angle,
-                        nobj,
-                        flux,
-                        area
+                        nobjs[nobjs.len() - 1],
+                        fluxes[fluxes.len() - 1],
+                        areas[areas.len() - 1]
                     );
                 }
 
@@ -210,80 +470,435 @@ fn main() {
                     if opt.verbose {
                         println!("Deleting image at {}", filename);
                     }
-                    remove_file(filename).expect("Could not remove file!");
+                    if let Err(e) = remove_file(&filename) {
+                        println!("Could not remove {}: {}", filename, e);
+                    }
                 }
             });
 
-            area /= opt.naverage as f64;
-            flux /= opt.naverage as f64;
-            nobj /= opt.naverage;
+            if fluxes.is_empty() {
+                println!("No usable images at angle {}, skipping it.", current_angle);
+                return None;
+            }
+
+            let flux = opt.combine.apply(&fluxes);
+            let area = opt.combine.apply(&areas);
+            let nobj = (nobjs.iter().sum::<usize>() as f64 / nobjs.len() as f64).round() as usize;
 
             if opt.verbose && opt.naverage > 1 {
-                println!("Averaging results --- Angle: {:.2}\tAverageNObj: {:.0}\tAverageSpotFlux: {:.1}\tAverageArea{:.0}\tNAveraged: {:.0}", current_angle, nobj, flux, area, opt.naverage);
+                println!("Combined results --- Angle: {:.2}\tNObj: {}\tSpotFlux: {:.1} ± {:.1}\tArea: {:.0} ± {:.0}\tNUsed: {} of {}", current_angle, nobj, flux.value, flux.std_err, area.value, area.std_err, flux.nused, fluxes.len());
             }
 
-            FrameData {
+            let frame = FrameData {
                 angle: *current_angle,
                 raw_angle,
                 nobj,
-                spotflux: flux,
-                spotarea: area,
+                spotflux: flux.value,
+                spotarea: area.value,
+                spotflux_err: flux.std_err,
+                spotarea_err: area.std_err,
+                spots,
+            };
+            if let Err(e) = session.journal.append(&frame) {
+                println!("Could not write frame to the run journal: {}", e);
             }
+            Some(frame)
         })
         .collect::<Vec<_>>();
+    frames.extend(measured);
+}
 
-    if opt.verbose {
-        println!("{}", serde_json::to_string_pretty(&data).unwrap());
+/// Measure the spot flux over the sweep, recording every frame in the run journal. When resuming
+/// a run, angles already in its journal are not measured again. Given model curves, the sweep is
+/// adaptive: the curves are refitted after each batch of angles to decide where to measure next.
+fn collect_frames(
+    opt: &Opt,
+    limits: TravelLimits,
+    rig: &Option<SimulatedRig>,
+    adaptive: Option<&TransmissionCurves>,
+) -> Vec<FrameData> {
+    let df_dir = "/tmp";
+
+    // let df_dir = env::var("DFREPOSITORIES");
+    // if df_dir.is_err() {
+    //     Error::with_description(
+    //         "Could not find the DFREPOSITORIES environment variable!",
+    //         ErrorKind::EmptyValue,
+    //     )
+    //     .exit();
+    // }
+    // let df_dir = df_dir.unwrap();
+
+    // let df_dir = format!("{}\\Dragonfly-MaximDL\\", df_dir);
+
+    let config = SweepConfig {
+        start: opt.start,
+        end: opt.end,
+        nstep: opt.nstep,
+        exptime: opt.exptime,
+        filter: opt.filter.to_string(),
+        simulation: opt.simulation,
+    };
+    let (journal, mut frames) = match &opt.resume {
+        Some(run_id) => RunJournal::resume(&opt.journal_dir, run_id, &config),
+        None => {
+            RunJournal::create(&opt.journal_dir, &new_run_id(), &config).map(|j| (j, Vec::new()))
+        }
+    }
+    .unwrap_or_else(|e| Error::with_description(&e.to_string(), ErrorKind::Io).exit());
+    println!(
+        "Run ID: {} (journal at {})",
+        journal.run_id(),
+        journal.path().display()
+    );
+
+    let tilter = if opt.simulation {
+        FilterTilter::simulation(format!("{}/ft-simulation.json", df_dir), opt.verbose)
+            .unwrap_or_else(|e| Error::with_description(&e.to_string(), ErrorKind::Io).exit())
+    } else {
+        FilterTilter::serial(&opt.port, opt.verbose)
     }
+    .limits(limits)
+    .retries(opt.retries);
 
-    let datatilt = data.iter().map(|x| x.angle - 180.).collect::<Vector>();
-    let dataflux = data.iter().map(|x| x.spotflux).collect::<Vector>();
-    let datafluxnorm = &dataflux / dataflux.max();
+    // Pick the spot up where the interrupted run left it.
+    let mut spots = SpotFinder::new();
+    for &choice in frames.iter().flat_map(|f| &f.spots) {
+        spots.record(choice);
+    }
 
-    println!("{:?}", datatilt);
-    println!("{:?}", datafluxnorm);
-
-    let fractions = linspace(0., 1., 100);
-    let shifts = linspace(-25., 25., 500);
-
-    let result = fractions
-        .par_iter()
-        .map(|&frac| {
-            let totalflux = {
-                MODEL_FLUX
-                    .iter()
-                    .zip(MODEL_FLUX_NII)
-                    .map(|(x, y)| x + frac * y)
-                    .collect::<Vector>()
-            };
-            let totalfluxnorm = &totalflux / totalflux.max();
-            let shift_interp = |x: &[f64]| {
-                interp1d_linear_unchecked(
-                    &MODEL_TILT,
-                    &totalfluxnorm,
-                    x,
-                    ExtrapolationMode::Fill(0., 0.),
-                )
-            };
-            let residual = |s: f64| {
-                ((shift_interp(&(&datatilt - s)) - &datafluxnorm)
-                    .powi(2)
-                    * (1. + &datafluxnorm))
-                    .sum()
-            };
-            let res_wrt_shifts = shifts.par_iter().map(|&x| residual(x)).collect::<Vector>();
-            let min_idx = argmin(&res_wrt_shifts);
+    let mut session = Session {
+        tilter,
+        journal,
+        spots,
+        darks: if opt.darks {
+            Some(
+                DarkLibrary::new(&opt.dark_dir)
+                    .nframes(opt.ndarks)
+                    .temperature_tolerance(opt.dark_tolerance),
+            )
+        } else {
+            None
+        },
+    };
+
+    let curves = match adaptive {
+        Some(curves) => curves,
+        None => {
+            let raw_angles = uniform_angles(opt.start, opt.end, opt.nstep);
+            measure_angles(opt, df_dir, &raw_angles, &mut session, rig, &mut frames);
+            return frames;
+        }
+    };
+
+    let sweep = AdaptiveSweep::new(opt.start, opt.end)
+        .ncoarse(opt.nstep)
+        .batch(opt.refine_batch)
+        .max_steps(opt.max_steps)
+        .target_uncertainty(opt.target_uncertainty);
+    let coarse = sweep.coarse_angles();
+    let mut tried = coarse.clone();
+    let resumed = frames
+        .iter()
+        .map(|f| f.angle)
+        .filter(|a| !tried.contains(a))
+        .collect::<Vec<_>>();
+    tried.extend(resumed);
+    measure_angles(opt, df_dir, &coarse, &mut session, rig, &mut frames);
+
+    while frames.len() >= 2 {
+        let datatilt = frames
+            .iter()
+            .map(|x| x.raw_angle - 180.)
+            .collect::<Vec<_>>();
+        let dataflux = frames.iter().map(|x| x.spotflux).collect::<Vec<_>>();
+        let dataerr = frames.iter().map(|x| x.spotflux_err).collect::<Vec<_>>();
+        let fit = match fit_tilt_calibration(curves, &datatilt, &dataflux, Some(dataerr.as_slice()))
+        {
+            Ok(fit) => fit,
+            Err(e) => {
+                println!("{}; stopping the adaptive sweep.", e);
+                break;
+            }
+        };
+        let uncertainty =
+            jacobian_uncertainty(curves, &datatilt, &dataflux, Some(dataerr.as_slice()), &fit);
+        println!(
+            "{} angles measured, tilt shift {} ± {}",
+            frames.len(),
+            fit.tilt_shift,
+            uncertainty.tilt_shift.std_err
+        );
+        if sweep.is_done(&uncertainty, tried.len()) {
+            break;
+        }
+
+        let next = sweep.refine_angles(curves, &fit, &tried);
+        if next.is_empty() {
+            break;
+        }
+        tried.extend(&next);
+        measure_angles(opt, df_dir, &next, &mut session, rig, &mut frames);
+    }
+
+    frames
+}
+
+fn main() {
+    for (path, reason) in FilterRegistry::global().skipped() {
+        eprintln!("Skipping filter {}: {}", path.display(), reason);
+    }
+
+    let opt = Opt::from_args();
+    let limits = TravelLimits::new(opt.min_angle, opt.max_angle).unwrap_or_else(|e| {
+        Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+    });
+    if !limits.contains(opt.start) || !limits.contains(opt.end) {
+        Error::with_description(
+            &format!(
+                "Start and end angles must be within the travel limits [{}, {}].",
+                limits.min, limits.max
+            ),
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    if opt.start > opt.end {
+        Error::with_description(
+            "Start angle must be less than end angle.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    if opt.nwalkers < 8 {
+        Error::with_description(
+            "Number of walkers must be at least 8 (twice the number of parameters).",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    if opt.nstep < 2 {
+        Error::with_description(
+            "Number of steps must be at least 2.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    if opt.tolerance <= 0. || opt.settle_timeout <= 0. {
+        Error::with_description(
+            "Tolerance and settle time must be positive.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    if opt.adaptive && (opt.target_uncertainty <= 0. || opt.refine_batch == 0) {
+        Error::with_description(
+            "Target uncertainty and refinement batch size must be positive.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    if opt.exptime <= 0. {
+        Error::with_description("Exposure time must be positive.", ErrorKind::InvalidValue).exit()
+    }
+    if opt.darks && (opt.ndarks == 0 || opt.dark_tolerance < 0.) {
+        Error::with_description(
+            "Number of darks must be positive and the dark temperature tolerance non-negative.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+
+    if opt.effective_index <= 1. || opt.filter_bandwidth.map_or(false, |b| b <= 0.) {
+        Error::with_description(
+            "Effective index must be greater than 1 and bandwidth positive.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    if opt.interpolate_tilt && InterpolatedTransmission::for_filter(opt.filter).is_none() {
+        Error::with_description(
+            &format!(
+                "No measurement of {} at a second tilt to interpolate with.",
+                opt.filter
+            ),
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    if opt.laser_lines.is_empty() || opt.laser_fwhm <= 0. {
+        Error::with_description(
+            "At least one laser line with a positive FWHM is needed.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
 
-            (frac, shifts[min_idx], res_wrt_shifts[min_idx])
+    let rig = if opt.simulation {
+        Some(SimulatedRig {
+            zeropoint_offset: opt.sim_offset,
+            nii_fraction: opt.sim_nii_fraction,
+            noise: opt.sim_noise,
+            ..Default::default()
         })
-        .collect::<Vec<_>>();
+    } else {
+        None
+    };
+    if let Some(Err(e)) = rig.as_ref().map(|rig| rig.filter.curve()) {
+        Error::with_description(
+            &format!("Cannot simulate the rig: {}", e),
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
 
-    let best = result
-        .iter()
-        .min_by(|a, b| a.2.partial_cmp(&b.2).unwrap())
-        .unwrap()
-        .to_owned();
+    let nominal = FilterSpec::nominal(opt.filter).unwrap_or_else(|e| {
+        Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+    });
+    let builder = TransmissionModel::builder()
+        .filter(opt.filter)
+        .wavefront(opt.wavefront)
+        .filter_cwl(opt.filter_cwl.unwrap_or(nominal.cwl))
+        .effective_index(opt.effective_index)
+        .bandwidth(opt.filter_bandwidth.unwrap_or(nominal.bandwidth))
+        .laser_lines(&opt.laser_lines)
+        .laser_fwhm(opt.laser_fwhm)
+        .interpolate_tilt(opt.interpolate_tilt)
+        .cache_dir(if opt.no_cache {
+            None
+        } else {
+            Some(&opt.cache_dir)
+        });
+
+    let data = match &opt.refit {
+        Some(run_id) => read_journal(journal_path(&opt.journal_dir, run_id))
+            .map(|(_, frames)| frames)
+            .unwrap_or_else(|e| Error::with_description(&e.to_string(), ErrorKind::Io).exit()),
+        None if opt.adaptive => {
+            let curves = builder
+                .clone()
+                .build()
+                .unwrap_or_else(|e| Error::with_description(&e.to_string(), ErrorKind::Io).exit());
+            collect_frames(&opt, limits, &rig, Some(&curves))
+        }
+        None => collect_frames(&opt, limits, &rig, None),
+    };
+
+    if data.len() < 2 {
+        Error::with_description(
+            "Fewer than two angles were measured successfully, cannot fit.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+
+    if opt.verbose {
+        println!("{}", serde_json::to_string_pretty(&data).unwrap());
+    }
+
+    let datatilt = data.iter().map(|x| x.raw_angle - 180.).collect::<Vec<_>>();
+    let dataflux = data.iter().map(|x| x.spotflux).collect::<Vec<_>>();
+    let dataerr = data.iter().map(|x| x.spotflux_err).collect::<Vec<_>>();
+    if let Err(e) = check_fit_data(&datatilt, &dataflux) {
+        Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+    }
+    match fill_errors(&dataerr) {
+        Some((_, nfilled)) if nfilled > 0 => println!(
+            "{} of {} angles have no flux uncertainty (e.g. from a single frame); they are given \
+             the median of the others.",
+            nfilled,
+            dataerr.len()
+        ),
+        Some(_) => {}
+        None => println!(
+            "No angle has a flux uncertainty (e.g. from single frames); weighting the fit by flux."
+        ),
+    }
 
-    println!("Nii strength: {}", best.0);
-    println!("Tilt shift: {}", best.1);
+    println!("{:?}", datatilt);
+    println!("{:?}", normalize_flux(&dataflux));
+
+    let free = FreeParameters {
+        filter_cwl: opt.fit_filter_cwl,
+        effective_index: opt.fit_effective_index,
+        laser_fwhm: opt.fit_laser_fwhm,
+    };
+    let (best, curves) = if free.any() {
+        let fit = fit_filter_calibration(
+            builder.model(),
+            free,
+            &datatilt,
+            &dataflux,
+            Some(dataerr.as_slice()),
+        )
+        .unwrap_or_else(|e| {
+            Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+        });
+        println!("Filter CWL: {} nm", fit.model.spec.cwl);
+        println!("Effective index: {}", fit.model.spec.effective_index);
+        println!("Laser FWHM: {} nm", fit.model.laser_fwhm);
+        let curves = fit.model.compute().unwrap_or_else(|e| {
+            Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+        });
+        (fit.tilt, curves)
+    } else {
+        let curves = builder
+            .build()
+            .unwrap_or_else(|e| Error::with_description(&e.to_string(), ErrorKind::Io).exit());
+        let fit = fit_tilt_calibration(&curves, &datatilt, &dataflux, Some(dataerr.as_slice()))
+            .unwrap_or_else(|e| {
+                Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+            });
+        (fit, curves)
+    };
+
+    let uncertainty = match opt.bootstrap {
+        Some(nboot) => bootstrap_uncertainty(
+            &curves,
+            &datatilt,
+            &dataflux,
+            Some(dataerr.as_slice()),
+            &best,
+            nboot,
+        ),
+        None => jacobian_uncertainty(
+            &curves,
+            &datatilt,
+            &dataflux,
+            Some(dataerr.as_slice()),
+            &best,
+        ),
+    };
+
+    println!(
+        "Nii strength: {} ± {} ({}% CI [{}, {}])",
+        best.nii_fraction,
+        uncertainty.nii_fraction.std_err,
+        CONFIDENCE_LEVEL * 100.,
+        uncertainty.nii_fraction.lower,
+        uncertainty.nii_fraction.upper
+    );
+    println!(
+        "Tilt shift: {} ± {} ({}% CI [{}, {}])",
+        best.tilt_shift,
+        uncertainty.tilt_shift.std_err,
+        CONFIDENCE_LEVEL * 100.,
+        uncertainty.tilt_shift.lower,
+        uncertainty.tilt_shift.upper
+    );
+    println!("Correlation: {}", uncertainty.correlation);
+    println!("Reduced chi-square: {}", uncertainty.reduced_chi2);
+
+    if let Some(nsteps) = opt.mcmc {
+        if let Err(e) =
+            sample_posterior(&opt, &curves, &datatilt, &dataflux, &dataerr, &best, nsteps)
+        {
+            println!("MCMC sampling failed: {}", e);
+        }
+    }
+
+    if let Some(rig) = &rig {
+        println!("Injected Nii strength: {}", rig.nii_fraction);
+        println!("Injected tilt shift: {}", rig.zeropoint_offset);
+    }
 }
@@ -0,0 +1,77 @@
+use std::{sync::atomic::AtomicBool, time::Duration};
+
+use clap::{Error, ErrorKind};
+use dragonfly::calibration::emulator::{EmulatorConfig, FTEmulator};
+use structopt::{
+    clap::AppSettings::{ColorAuto, ColoredHelp},
+    StructOpt,
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "Dragonfly: Filter-Tilter Emulator",
+    about = "Emulates a filter-tilter unit on a pseudo-terminal.",
+    author
+)]
+#[structopt(setting(ColorAuto), setting(ColoredHelp))]
+struct Opt {
+    /// Zero point the emulated unit starts with.
+    #[structopt(long, default_value = "0.")]
+    zeropoint: f64,
+    /// Raw angle the emulated unit starts at.
+    #[structopt(long, default_value = "171.", name = "raw_angle")]
+    rawangle: f64,
+    /// Delay in milliseconds before each reply is sent.
+    #[structopt(long, default_value = "0", name = "latency_ms")]
+    latency: u64,
+    /// Probability that a reply is garbled. Must be in [0., 1.].
+    #[structopt(long, default_value = "0.")]
+    garble: f64,
+    /// Hang up after answering this many commands.
+    #[structopt(long, name = "ncommands")]
+    disconnect_after: Option<usize>,
+    /// How fast the filter moves, in degrees per second. Moves are instantaneous if not given.
+    #[structopt(long, name = "degrees_per_second")]
+    slew_rate: Option<f64>,
+    /// Whether to be verbose and print messages.
+    #[structopt(long, short = "v")]
+    verbose: bool,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if opt.garble < 0. || opt.garble > 1. {
+        Error::with_description(
+            "Garble probability must be between 0 and 1.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+
+    let emulator = FTEmulator::new(EmulatorConfig {
+        zeropoint: opt.zeropoint,
+        rawangle: opt.rawangle,
+        latency: Duration::from_millis(opt.latency),
+        garble_probability: opt.garble,
+        disconnect_after: opt.disconnect_after,
+        slew_rate: opt.slew_rate,
+        verbose: opt.verbose,
+    })
+    .unwrap_or_else(|e| {
+        Error::with_description(
+            &format!("Could not create pseudo-terminal: {}", e),
+            ErrorKind::Io,
+        )
+        .exit()
+    });
+
+    println!(
+        "Emulated filter-tilter listening on {}",
+        emulator.port_name()
+    );
+
+    let stop = AtomicBool::new(false);
+    if let Err(e) = emulator.run(&stop) {
+        Error::with_description(&format!("Emulator failed: {}", e), ErrorKind::Io).exit()
+    }
+}
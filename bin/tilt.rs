@@ -0,0 +1,266 @@
+use clap::{Error, ErrorKind};
+use dragonfly::calibration::{
+    velocity_to_redshift, EmissionLine, Filter, FilterRegistry, FilterSpec, LinePlanner,
+    ScanComparison, TiltScan, TransmissionModel, TravelLimits, Wavefront, CONFIDENCE_LEVEL,
+    DEFAULT_LINE_FWHM,
+};
+use std::path::PathBuf;
+use structopt::{
+    clap::AppSettings::{ColorAuto, ColoredHelp},
+    StructOpt,
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "Dragonfly: Tilt",
+    about = "Tools for working with calibrated filter-tilter units.",
+    author
+)]
+#[structopt(setting(ColorAuto), setting(ColoredHelp))]
+enum Opt {
+    /// Work out the raw tilter angle that centres the filter on an emission line.
+    Plan(PlanOpt),
+    /// Fit the tilt shift of each unit to a tilt scan of a source, and compare the units.
+    Scans(ScansOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct PlanOpt {
+    /// Emission line to target: halpha, nii6548, nii6583, sii6716, sii6731, oiii4959 or
+    /// oiii5007.
+    #[structopt(long, required_unless = "rest_wavelength_nm")]
+    line: Option<EmissionLine>,
+    /// Rest wavelength in nm of a line not in the catalogue.
+    #[structopt(long, name = "rest_wavelength_nm", conflicts_with = "line")]
+    rest_wavelength: Option<f64>,
+    /// Recession velocity of the target in km/s.
+    #[structopt(long, name = "km_per_s", conflicts_with = "redshift")]
+    velocity: Option<f64>,
+    /// Redshift of the target.
+    #[structopt(long)]
+    redshift: Option<f64>,
+    /// Calibrated tilt of the filter, in degrees, at a raw angle of 180.
+    #[structopt(long, default_value = "0.", name = "tilt_shift_degrees")]
+    tilt_shift: f64,
+    /// Filter in the light path, by its name in the filter directory (`$DRAGONFLY_FILTER_DIR`),
+    /// e.g. `3.1BPF_0deg`.
+    #[structopt(long, default_value = "3.1BPF_0deg")]
+    filter: Filter,
+    /// Wavefront illuminating the filter: `coll`, `3deg` or `22deg`.
+    #[structopt(long, default_value = "coll")]
+    wavefront: Wavefront,
+    /// Central wavelength of the filter at normal incidence in nm, if different from the filter's
+    /// nominal CWL.
+    #[structopt(long, name = "filter_cwl_nm")]
+    filter_cwl: Option<f64>,
+    /// Effective refractive index of the filter.
+    #[structopt(long, default_value = "2.1")]
+    effective_index: f64,
+    /// FWHM of the emission lines, in nm.
+    #[structopt(long, name = "line_fwhm_nm")]
+    line_fwhm: Option<f64>,
+    /// Lowest raw angle this unit can safely be driven to.
+    #[structopt(long, default_value = "160.", name = "min_degrees")]
+    min_angle: f64,
+    /// Highest raw angle this unit can safely be driven to.
+    #[structopt(long, default_value = "200.", name = "max_degrees")]
+    max_angle: f64,
+    /// Print the plan as JSON.
+    #[structopt(long)]
+    json: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct ScansOpt {
+    /// Scan files of `tilt flux` lines, the first being a reference measurement, e.g.
+    /// `data/PNeMeasurements/measure_source_301.txt`.
+    #[structopt(required = true, parse(from_os_str))]
+    files: Vec<PathBuf>,
+    /// Filter whose transmission curve the fit models, by its name in the filter directory
+    /// (`$DRAGONFLY_FILTER_DIR`), e.g. `3.1BPF_0deg`.
+    #[structopt(long, default_value = "3.1BPF_0deg")]
+    filter: Filter,
+    /// Wavefront illuminating the filter: `coll`, `3deg` or `22deg`.
+    #[structopt(long, default_value = "coll")]
+    wavefront: Wavefront,
+    /// Central wavelength of the filter at normal incidence in nm, if different from the filter's
+    /// nominal CWL.
+    #[structopt(long, name = "filter_cwl_nm")]
+    filter_cwl: Option<f64>,
+    /// Effective refractive index of the filter.
+    #[structopt(long, default_value = "2.1")]
+    effective_index: f64,
+    /// Central wavelengths of the source lines in nm, Hα first.
+    #[structopt(
+        long,
+        default_value = "656.3,658.5",
+        use_delimiter = true,
+        name = "lines_nm"
+    )]
+    lines: Vec<f64>,
+    /// FWHM of each source line, in nm.
+    #[structopt(long, default_value = "0.61", name = "fwhm_nm")]
+    fwhm: f64,
+    /// Directory to cache model transmission curves in.
+    #[structopt(long, default_value = "cache")]
+    cache_dir: String,
+    /// Recompute the model transmission curves without reading or writing the cache.
+    #[structopt(long)]
+    no_cache: bool,
+    /// Print the fits and comparison as JSON.
+    #[structopt(long)]
+    json: bool,
+}
+
+fn scans(opt: ScansOpt) {
+    if opt.lines.is_empty() || opt.fwhm <= 0. {
+        Error::with_description(
+            "At least one source line with a positive FWHM is needed.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+
+    let nominal = FilterSpec::nominal(opt.filter).unwrap_or_else(|e| {
+        Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+    });
+    let curves = TransmissionModel::builder()
+        .filter(opt.filter)
+        .wavefront(opt.wavefront)
+        .filter_cwl(opt.filter_cwl.unwrap_or(nominal.cwl))
+        .effective_index(opt.effective_index)
+        .laser_lines(&opt.lines)
+        .laser_fwhm(opt.fwhm)
+        .cache_dir(if opt.no_cache {
+            None
+        } else {
+            Some(&opt.cache_dir)
+        })
+        .build()
+        .unwrap_or_else(|e| Error::with_description(&e.to_string(), ErrorKind::Io).exit());
+
+    let fits = opt
+        .files
+        .iter()
+        .map(|path| {
+            TiltScan::from_file(path)
+                .unwrap_or_else(|e| Error::with_description(&e.to_string(), ErrorKind::Io).exit())
+                .fit(&curves)
+                .unwrap_or_else(|e| {
+                    Error::with_description(
+                        &format!("{}: {}", path.display(), e),
+                        ErrorKind::InvalidValue,
+                    )
+                    .exit()
+                })
+        })
+        .collect::<Vec<_>>();
+    let comparison = ScanComparison::new(&fits);
+
+    if opt.json {
+        let out = serde_json::json!({ "fits": fits, "comparison": comparison });
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        return;
+    }
+
+    println!(
+        "{:>8} {:>7} {:>12} {:>10} {:>12} {:>10} {:>11} {:>8}",
+        "Unit", "Points", "Tilt shift", "±", "NII frac", "±", "Deviation", "Sigma"
+    );
+    for (fit, unit) in fits.iter().zip(&comparison.units) {
+        println!(
+            "{:>8} {:>7} {:>12.3} {:>10.3} {:>12.3} {:>10.3} {:>11.3} {:>8.1}",
+            fit.unit,
+            fit.npoints,
+            fit.fit.tilt_shift,
+            fit.uncertainty.tilt_shift.std_err,
+            fit.fit.nii_fraction,
+            fit.uncertainty.nii_fraction.std_err,
+            unit.deviation,
+            unit.significance
+        );
+    }
+    println!(
+        "Mean tilt shift: {:.3} degrees, spread {:.3}, range {:.3}",
+        comparison.mean_tilt_shift, comparison.spread, comparison.range
+    );
+    for fit in &fits {
+        println!(
+            "Unit {}: tilt shift {}% CI [{:.3}, {:.3}], reduced chi-square {:.3}",
+            fit.unit,
+            CONFIDENCE_LEVEL * 100.,
+            fit.uncertainty.tilt_shift.lower,
+            fit.uncertainty.tilt_shift.upper,
+            fit.uncertainty.reduced_chi2
+        );
+    }
+}
+
+fn plan(opt: PlanOpt) {
+    if opt.min_angle >= opt.max_angle {
+        Error::with_description(
+            "Minimum travel limit must be less than the maximum.",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+
+    let rest_wavelength = match (opt.line, opt.rest_wavelength) {
+        (Some(line), _) => line.rest_wavelength(),
+        (None, Some(wavelength)) => wavelength,
+        (None, None) => unreachable!(),
+    };
+    let redshift = opt
+        .redshift
+        .or_else(|| opt.velocity.map(velocity_to_redshift))
+        .unwrap_or(0.);
+
+    let limits = TravelLimits::new(opt.min_angle, opt.max_angle).unwrap_or_else(|e| {
+        Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+    });
+    let nominal = FilterSpec::nominal(opt.filter).unwrap_or_else(|e| {
+        Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+    });
+    let planner = LinePlanner::new(opt.filter, opt.tilt_shift)
+        .unwrap_or_else(|e| Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit())
+        .spec(FilterSpec {
+            cwl: opt.filter_cwl.unwrap_or(nominal.cwl),
+            effective_index: opt.effective_index,
+            ..nominal
+        })
+        .wavefront(opt.wavefront)
+        .line_fwhm(opt.line_fwhm.unwrap_or(DEFAULT_LINE_FWHM))
+        .limits(limits);
+
+    let plan = planner.plan(rest_wavelength, redshift).unwrap_or_else(|e| {
+        Error::with_description(&e.to_string(), ErrorKind::InvalidValue).exit()
+    });
+
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&plan).unwrap());
+        return;
+    }
+
+    println!("Observed wavelength: {:.3} nm", plan.wavelength);
+    println!("Filter tilt: {:.2} degrees", plan.tilt);
+    println!("Raw angle: {:.2}", plan.raw_angle);
+    println!("Peak transmission: {:.3}", plan.peak_transmission);
+    for c in &plan.contaminants {
+        println!(
+            "Contaminant {:?} at {:.3} nm: {:.3} of target flux",
+            c.line, c.wavelength, c.relative_flux
+        );
+    }
+    println!("Contamination fraction: {:.3}", plan.contamination);
+}
+
+fn main() {
+    for (path, reason) in FilterRegistry::global().skipped() {
+        eprintln!("Skipping filter {}: {}", path.display(), reason);
+    }
+
+    match Opt::from_args() {
+        Opt::Plan(opt) => plan(opt),
+        Opt::Scans(opt) => scans(opt),
+    }
+}
@@ -0,0 +1,158 @@
+//! Combining the measurements from several frames taken at one tilt into a single value with an
+//! uncertainty, rejecting outliers such as cosmic rays or satellites picked as the spot.
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::cmp_nan_last;
+
+/// Efficiency correction for the standard error of a median of normally distributed values.
+const MEDIAN_STD_ERR: f64 = 1.2533141373155001;
+/// Ratio of the standard deviation to the median absolute deviation for normal values.
+const MAD_TO_STD: f64 = 1.482602218505602;
+
+/// How the frames at one tilt are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Combine {
+    Mean,
+    Median,
+    /// Mean after repeatedly rejecting values more than `sigma` standard deviations from the
+    /// median, for at most `iterations` rounds. The standard deviation is estimated from the
+    /// median absolute deviation, so that a single outlier cannot hide itself by inflating it.
+    SigmaClip {
+        sigma: f64,
+        iterations: usize,
+    },
+}
+
+impl Default for Combine {
+    fn default() -> Self {
+        Combine::SigmaClip {
+            sigma: 3.,
+            iterations: 5,
+        }
+    }
+}
+
+impl FromStr for Combine {
+    type Err = String;
+
+    /// Parse `mean`, `median` or `sigma-clip` (with the default threshold).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mean" => Ok(Combine::Mean),
+            "median" => Ok(Combine::Median),
+            "sigma-clip" | "sigmaclip" | "clip" => Ok(Combine::default()),
+            _ => Err(format!(
+                "unknown combination {:?}, expected mean, median or sigma-clip",
+                s
+            )),
+        }
+    }
+}
+
+/// The combined value of several measurements.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Combined {
+    pub value: f64,
+    /// Standard error of `value` from the scatter of the values used. Zero with fewer than two.
+    pub std_err: f64,
+    /// Number of values used, after any rejection.
+    pub nused: usize,
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Sample standard deviation, or zero for fewer than two values.
+fn std_dev(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.;
+    }
+    let m = mean(values);
+    (values.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (values.len() - 1) as f64).sqrt()
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(cmp_nan_last);
+    let n = sorted.len();
+    if n % 2 == 1 {
+        sorted[n / 2]
+    } else {
+        0.5 * (sorted[n / 2 - 1] + sorted[n / 2])
+    }
+}
+
+impl Combine {
+    /// Combine `values`, which must not be empty.
+    pub fn apply(&self, values: &[f64]) -> Combined {
+        assert!(!values.is_empty(), "Nothing to combine.");
+        let n = values.len();
+        match *self {
+            Combine::Mean => Combined {
+                value: mean(values),
+                std_err: std_dev(values) / (n as f64).sqrt(),
+                nused: n,
+            },
+            Combine::Median => Combined {
+                value: median(values),
+                std_err: MEDIAN_STD_ERR * std_dev(values) / (n as f64).sqrt(),
+                nused: n,
+            },
+            Combine::SigmaClip { sigma, iterations } => {
+                let mut kept = values.to_vec();
+                for _ in 0..iterations {
+                    let centre = median(&kept);
+                    let deviations = kept.iter().map(|v| (v - centre).abs()).collect::<Vec<_>>();
+                    let scale = match MAD_TO_STD * median(&deviations) {
+                        mad if mad > 0. => mad,
+                        _ => std_dev(&kept),
+                    };
+                    let clipped = kept
+                        .iter()
+                        .cloned()
+                        .filter(|v| (v - centre).abs() <= sigma * scale)
+                        .collect::<Vec<_>>();
+                    if clipped.len() == kept.len() || clipped.is_empty() {
+                        break;
+                    }
+                    kept = clipped;
+                }
+                Combine::Mean.apply(&kept)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rejects_outlier() {
+        let mut values = vec![100., 101., 99., 100.5, 99.5, 100., 101., 99.];
+        values.push(5000.);
+
+        let clipped = Combine::default().apply(&values);
+        assert_eq!(clipped.nused, 8);
+        assert!((clipped.value - 100.).abs() < 1e-12);
+        assert!(clipped.std_err > 0. && clipped.std_err < 1.);
+
+        let median = Combine::Median.apply(&values);
+        assert_eq!(median.value, 100.);
+        assert!(Combine::Mean.apply(&values).value > 600.);
+    }
+
+    #[test]
+    fn test_single_value() {
+        for combine in &[Combine::Mean, Combine::Median, Combine::default()] {
+            let combined = combine.apply(&[42.]);
+            assert_eq!(combined.value, 42.);
+            assert_eq!(combined.std_err, 0.);
+            assert_eq!(combined.nused, 1);
+        }
+    }
+}
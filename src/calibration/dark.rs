@@ -0,0 +1,451 @@
+//! Dark subtraction for calibration frames. Darks are taken with the shutter closed at the sweep
+//! exposure time, median-combined into a master dark and subtracted from each light frame before
+//! SExtractor sees it. A dark of the same length also carries the bias, so no separate bias
+//! frames are needed. Master darks are kept on disk and reused while the exposure time and
+//! sensor temperature match.
+
+use std::{
+    fs::{create_dir_all, read_dir, remove_file},
+    path::{Path, PathBuf},
+};
+
+use fitsio::{
+    hdu::{FitsHdu, HduInfo},
+    images::{ImageDescription, ImageType as FitsImageType},
+    FitsFile,
+};
+
+use super::combine::Combine;
+use crate::{
+    core::expose::{expose, wait_for, ImageType},
+    error::{Error, Result},
+    utils::cmp_nan_last,
+};
+
+/// Directory master darks are kept in by default.
+pub const DEFAULT_DARK_DIR: &str = "darks";
+/// FITS header key holding the sensor temperature, in degrees Celsius.
+pub const TEMPERATURE_KEY: &str = "CCD-TEMP";
+/// FITS header key holding the exposure time, in seconds.
+pub const EXPTIME_KEY: &str = "EXPTIME";
+/// FITS header key holding the number of darks in a master dark.
+pub const NCOMBINE_KEY: &str = "NCOMBINE";
+
+/// Exposure times closer than this, in seconds, are taken to be the same.
+const EXPTIME_TOLERANCE: f64 = 1e-3;
+
+/// Median of `values`, found by partially reordering them in place rather than sorting a copy.
+fn median_in_place(values: &mut [f64]) -> f64 {
+    let n = values.len();
+    let (lower, &mut upper, _) = values.select_nth_unstable_by(n / 2, cmp_nan_last);
+    if n % 2 == 1 {
+        upper
+    } else {
+        0.5 * (lower.iter().cloned().fold(f64::MIN, f64::max) + upper)
+    }
+}
+
+/// Whether a dark taken with exposure time `dark_exptime` at `dark_temperature` can be used for
+/// frames with exposure time `exptime` taken at `temperature`, give or take `tolerance` degrees.
+fn conditions_match(
+    (dark_exptime, dark_temperature): (f64, f64),
+    exptime: f64,
+    temperature: f64,
+    tolerance: f64,
+) -> bool {
+    (dark_exptime - exptime).abs() < EXPTIME_TOLERANCE
+        && (dark_temperature - temperature).abs() <= tolerance
+}
+
+/// The pixels of a FITS image, stored row by row, and the sensor temperature it was taken at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitsImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<f64>,
+    /// Sensor temperature in degrees Celsius, if the header records it.
+    pub temperature: Option<f64>,
+}
+
+impl FitsImage {
+    /// Read the primary image of a FITS file.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut fptr = FitsFile::open(path.as_ref())?;
+        let hdu = fptr.primary_hdu()?;
+        let (height, width) = match &hdu.info {
+            HduInfo::ImageInfo { shape, .. } if shape.len() == 2 => (shape[0], shape[1]),
+            _ => {
+                return Err(Error::Dark(format!(
+                    "{} is not a 2D image",
+                    path.as_ref().display()
+                )))
+            }
+        };
+        let pixels: Vec<f64> = hdu.read_image(&mut fptr)?;
+        let temperature = hdu.read_key::<f64>(&mut fptr, TEMPERATURE_KEY).ok();
+        Ok(FitsImage {
+            width,
+            height,
+            pixels,
+            temperature,
+        })
+    }
+
+    /// Write the image to a FITS file, overwriting any existing file.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.create(path)?;
+        Ok(())
+    }
+
+    /// Write the image and temperature, returning the file so that more keys can be added.
+    fn create<P: AsRef<Path>>(&self, path: P) -> Result<(FitsFile, FitsHdu)> {
+        let description = ImageDescription {
+            data_type: FitsImageType::Double,
+            dimensions: &[self.height, self.width],
+        };
+        let mut fptr = FitsFile::create(path.as_ref())
+            .with_custom_primary(&description)
+            .overwrite()
+            .open()?;
+        let hdu = fptr.primary_hdu()?;
+        hdu.write_image(&mut fptr, &self.pixels[..])?;
+        if let Some(temperature) = self.temperature {
+            hdu.write_key(&mut fptr, TEMPERATURE_KEY, temperature)?;
+        }
+        Ok((fptr, hdu))
+    }
+}
+
+/// The pixel-by-pixel median of several darks of one exposure time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MasterDark {
+    /// Exposure time of the darks, in seconds.
+    pub exptime: f64,
+    /// Median sensor temperature of the darks, in degrees Celsius.
+    pub temperature: f64,
+    /// Number of darks combined.
+    pub nframes: usize,
+    pub image: FitsImage,
+}
+
+impl MasterDark {
+    /// Median-combine darks taken with exposure time `exptime`. Fails if there are none, if they
+    /// differ in size or if none of them records the sensor temperature.
+    pub fn combine(exptime: f64, darks: &[FitsImage]) -> Result<Self> {
+        let first = darks
+            .first()
+            .ok_or_else(|| Error::Dark("no darks to combine".to_owned()))?;
+        if let Some(other) = darks
+            .iter()
+            .find(|d| (d.width, d.height) != (first.width, first.height))
+        {
+            return Err(Error::Dark(format!(
+                "darks differ in size ({}x{} and {}x{})",
+                first.width, first.height, other.width, other.height
+            )));
+        }
+
+        let temperatures = darks
+            .iter()
+            .filter_map(|d| d.temperature)
+            .collect::<Vec<_>>();
+        if temperatures.is_empty() {
+            return Err(Error::Dark(format!(
+                "darks have no {} header",
+                TEMPERATURE_KEY
+            )));
+        }
+        let temperature = Combine::Median.apply(&temperatures).value;
+
+        let mut stack = vec![0.; darks.len()];
+        let pixels = (0..first.pixels.len())
+            .map(|i| {
+                for (value, dark) in stack.iter_mut().zip(darks) {
+                    *value = dark.pixels[i];
+                }
+                median_in_place(&mut stack)
+            })
+            .collect();
+
+        Ok(MasterDark {
+            exptime,
+            temperature,
+            nframes: darks.len(),
+            image: FitsImage {
+                width: first.width,
+                height: first.height,
+                pixels,
+                temperature: Some(temperature),
+            },
+        })
+    }
+
+    /// Whether this dark can be used for frames with exposure time `exptime` taken at
+    /// `temperature`, give or take `tolerance` degrees.
+    pub fn matches(&self, exptime: f64, temperature: f64, tolerance: f64) -> bool {
+        conditions_match(
+            (self.exptime, self.temperature),
+            exptime,
+            temperature,
+            tolerance,
+        )
+    }
+
+    /// Subtract the dark from a light frame of the same size.
+    pub fn subtract(&self, light: &FitsImage) -> Result<FitsImage> {
+        if (light.width, light.height) != (self.image.width, self.image.height) {
+            return Err(Error::Dark(format!(
+                "frame is {}x{} but the master dark is {}x{}",
+                light.width, light.height, self.image.width, self.image.height
+            )));
+        }
+        Ok(FitsImage {
+            width: light.width,
+            height: light.height,
+            pixels: light
+                .pixels
+                .iter()
+                .zip(&self.image.pixels)
+                .map(|(l, d)| l - d)
+                .collect(),
+            temperature: light.temperature,
+        })
+    }
+
+    /// Read only the exposure time and sensor temperature of a master dark written by `write`.
+    pub fn read_header<P: AsRef<Path>>(path: P) -> Result<(f64, f64)> {
+        let mut fptr = FitsFile::open(path.as_ref())?;
+        let hdu = fptr.primary_hdu()?;
+        let exptime = hdu.read_key::<f64>(&mut fptr, EXPTIME_KEY)?;
+        let temperature = hdu.read_key::<f64>(&mut fptr, TEMPERATURE_KEY)?;
+        Ok((exptime, temperature))
+    }
+
+    /// Read a master dark written by `write`.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let image = FitsImage::read(path.as_ref())?;
+        let mut fptr = FitsFile::open(path.as_ref())?;
+        let hdu = fptr.primary_hdu()?;
+        let exptime = hdu.read_key::<f64>(&mut fptr, EXPTIME_KEY)?;
+        let nframes = hdu.read_key::<i64>(&mut fptr, NCOMBINE_KEY)? as usize;
+        let temperature = image.temperature.ok_or_else(|| {
+            Error::Dark(format!(
+                "{} has no {} header",
+                path.as_ref().display(),
+                TEMPERATURE_KEY
+            ))
+        })?;
+        Ok(MasterDark {
+            exptime,
+            temperature,
+            nframes,
+            image,
+        })
+    }
+
+    /// Write the master dark to a FITS file, overwriting any existing file.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (mut fptr, hdu) = self.image.create(path)?;
+        hdu.write_key(&mut fptr, EXPTIME_KEY, self.exptime)?;
+        hdu.write_key(&mut fptr, NCOMBINE_KEY, self.nframes as i64)?;
+        Ok(())
+    }
+}
+
+/// Master darks on disk, taking new darks when none matches. The last master dark used is kept
+/// in memory, so a sweep only goes back to disk or the camera when the sensor temperature drifts.
+#[derive(Debug, Clone)]
+pub struct DarkLibrary {
+    dir: PathBuf,
+    /// Number of darks combined into a new master dark.
+    pub nframes: usize,
+    /// Largest difference in sensor temperature, in degrees Celsius, at which a master dark is
+    /// reused.
+    pub temperature_tolerance: f64,
+    current: Option<MasterDark>,
+}
+
+impl DarkLibrary {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        DarkLibrary {
+            dir: dir.as_ref().to_path_buf(),
+            nframes: 5,
+            temperature_tolerance: 1.,
+            current: None,
+        }
+    }
+
+    pub fn nframes(mut self, nframes: usize) -> Self {
+        self.nframes = nframes;
+        self
+    }
+
+    pub fn temperature_tolerance(mut self, temperature_tolerance: f64) -> Self {
+        self.temperature_tolerance = temperature_tolerance;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The master dark on disk closest in temperature to `temperature` among those that match,
+    /// if any. Only the headers are read to find it, so that the pixels of just one master dark
+    /// are loaded. Files that cannot be read as master darks are ignored.
+    pub fn find(&self, exptime: f64, temperature: f64) -> Result<Option<MasterDark>> {
+        if !self.dir.exists() {
+            return Ok(None);
+        }
+        let mut matching = Vec::new();
+        for entry in read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !(name.starts_with("master-dark-") && name.ends_with(".fits")) {
+                continue;
+            }
+            if let Ok(header) = MasterDark::read_header(&path) {
+                if conditions_match(header, exptime, temperature, self.temperature_tolerance) {
+                    matching.push((path, (header.1 - temperature).abs()));
+                }
+            }
+        }
+        matching.sort_by(|a, b| cmp_nan_last(&a.1, &b.1));
+        Ok(matching
+            .iter()
+            .find_map(|(path, _)| MasterDark::read(path).ok()))
+    }
+
+    /// Write a master dark to the library, returning its path.
+    pub fn store(&self, dark: &MasterDark) -> Result<PathBuf> {
+        create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!(
+            "master-dark-{}s-{:.1}C.fits",
+            dark.exptime, dark.temperature
+        ));
+        dark.write(&path)?;
+        Ok(path)
+    }
+
+    /// Take `nframes` darks with exposure time `exptime` and combine them. The individual darks
+    /// are deleted once read.
+    pub fn acquire(&self, exptime: f64) -> Result<MasterDark> {
+        create_dir_all(&self.dir)?;
+        let darks = (0..self.nframes)
+            .map(|i| {
+                let path = self.dir.join(format!("dark-{}.fits", i + 1));
+                wait_for(expose(ImageType::Dark, exptime, &path.to_string_lossy())?)?;
+                let dark = FitsImage::read(&path);
+                remove_file(&path)?;
+                dark
+            })
+            .collect::<Result<Vec<_>>>()?;
+        MasterDark::combine(exptime, &darks)
+    }
+
+    /// The master dark for frames with exposure time `exptime` taken at `temperature`: the one
+    /// in memory if it matches, else the closest match on disk, else a newly acquired one, which
+    /// is stored for next time.
+    pub fn master(&mut self, exptime: f64, temperature: f64) -> Result<&MasterDark> {
+        let dark = match self.current.take() {
+            Some(dark) if dark.matches(exptime, temperature, self.temperature_tolerance) => dark,
+            _ => match self.find(exptime, temperature)? {
+                Some(dark) => dark,
+                None => {
+                    let dark = self.acquire(exptime)?;
+                    self.store(&dark)?;
+                    dark
+                }
+            },
+        };
+        Ok(self.current.get_or_insert(dark))
+    }
+
+    /// Dark-subtract the light frame at `path`, taken with exposure time `exptime`, and write the
+    /// result next to it as `<name>-darksub.fits`. Returns the path of the result. Fails with
+    /// `Error::MissingTemperature` if the frame has no `CCD-TEMP` header, as no master dark can
+    /// be matched to it.
+    pub fn subtract(&mut self, path: &str, exptime: f64) -> Result<String> {
+        let light = FitsImage::read(path)?;
+        let temperature = light
+            .temperature
+            .ok_or_else(|| Error::MissingTemperature(path.to_owned()))?;
+        let subtracted = self.master(exptime, temperature)?.subtract(&light)?;
+        let out = format!("{}-darksub.fits", path.trim_end_matches(".fits"));
+        subtracted.write(&out)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    fn image(pixels: Vec<f64>, temperature: f64) -> FitsImage {
+        FitsImage {
+            width: 2,
+            height: 2,
+            pixels,
+            temperature: Some(temperature),
+        }
+    }
+
+    #[test]
+    fn test_combine_and_subtract() {
+        // A cosmic ray in the second dark is rejected by the median.
+        let darks = [
+            image(vec![100., 101., 102., 150.], -10.2),
+            image(vec![101., 5000., 103., 151.], -10.),
+            image(vec![99., 100., 101., 149.], -9.9),
+        ];
+        let master = MasterDark::combine(60., &darks).unwrap();
+        assert_eq!(master.image.pixels, vec![100., 101., 102., 150.]);
+        assert_eq!((master.temperature, master.nframes), (-10., 3));
+
+        let light = image(vec![110., 1101., 102., 150.], -10.3);
+        let subtracted = master.subtract(&light).unwrap();
+        assert_eq!(subtracted.pixels, vec![10., 1000., 0., 0.]);
+        assert_eq!(subtracted.temperature, Some(-10.3));
+
+        let small = FitsImage {
+            width: 1,
+            height: 1,
+            pixels: vec![0.],
+            temperature: None,
+        };
+        assert!(master.subtract(&small).is_err());
+        assert!(MasterDark::combine(60., &[darks[0].clone(), small]).is_err());
+        assert!(MasterDark::combine(60., &[]).is_err());
+    }
+
+    #[test]
+    fn test_median_in_place() {
+        assert_eq!(median_in_place(&mut [3., 1., 2.]), 2.);
+        assert_eq!(median_in_place(&mut [4., 1., 3., 2.]), 2.5);
+        assert_eq!(median_in_place(&mut [5.]), 5.);
+    }
+
+    #[test]
+    fn test_library_reuses_matching_dark() {
+        let dir = TempDir::new("darks");
+        let mut library = DarkLibrary::new(&dir).temperature_tolerance(0.5);
+        assert!(library.find(60., -10.).unwrap().is_none());
+
+        for &temperature in &[-10., -5.] {
+            let dark = image(vec![100., 101., 102., temperature], temperature);
+            library
+                .store(&MasterDark::combine(60., &[dark]).unwrap())
+                .unwrap();
+        }
+
+        let found = library.find(60., -9.7).unwrap().unwrap();
+        assert_eq!((found.exptime, found.temperature), (60., -10.));
+        assert_eq!(found.image.pixels[3], -10.);
+        assert!(library.find(60., -7.5).unwrap().is_none());
+        assert!(library.find(30., -10.).unwrap().is_none());
+
+        // Found on disk, so no darks need to be taken.
+        assert_eq!(library.master(60., -5.2).unwrap().temperature, -5.);
+        assert_eq!(library.master(60., -4.9).unwrap().temperature, -5.);
+    }
+}
@@ -1,21 +1,36 @@
 use std::{
-    fs::File,
-    io::{Read, Write},
+    io::{self, Read},
     time::Duration,
 };
 
 use chrono::Utc;
-use clap::Error;
+use lexical::parse;
 use serde::{Deserialize, Serialize};
-use serialport::SerialPort;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+use super::{
+    filter_tilter::{FilterTilter, TravelLimits},
+    spot::SpotChoice,
+};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FrameData {
     pub angle: f64,
     pub raw_angle: f64,
     pub nobj: usize,
     pub spotflux: f64,
     pub spotarea: f64,
+    /// Standard error of `spotflux` from the scatter between the frames combined at this angle.
+    /// Zero if unknown, e.g. for a single frame.
+    #[serde(default)]
+    pub spotflux_err: f64,
+    /// Standard error of `spotarea`, as for `spotflux_err`.
+    #[serde(default)]
+    pub spotarea_err: f64,
+    /// The catalog object taken as the spot in each frame at this angle, if the frames were
+    /// run through SExtractor.
+    #[serde(default)]
+    pub spots: Vec<SpotChoice>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -29,19 +44,170 @@ pub enum FTCommand {
     SETZERO,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum FTCommandResult {
     A,
     R,
     Z,
 }
 
+/// Baud rate of the filter-tilter's USB serial interface.
+pub const FT_BAUD_RATE: u32 = 9600;
+/// How long to wait for a reply from the filter-tilter before giving up.
+pub const FT_TIMEOUT: Duration = Duration::from_millis(5000);
+/// Terminator appended to every command sent to the filter-tilter.
+pub const FT_COMMAND_TERMINATOR: &str = "\r";
+/// Terminator marking the end of every reply from the filter-tilter.
+pub const FT_REPLY_TERMINATOR: u8 = b'\n';
+/// Longest reply we are willing to buffer before deciding the line is garbage.
+const FT_MAX_REPLY_LEN: usize = 64;
+
+impl FTCommand {
+    /// The keyword for this command on the wire.
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            FTCommand::GET => "GET",
+            FTCommand::SET => "SET",
+            FTCommand::GETRAW => "GETRAW",
+            FTCommand::SETRAW => "SETRAW",
+            FTCommand::ZERO => "ZERO",
+            FTCommand::GETZERO => "GETZERO",
+            FTCommand::SETZERO => "SETZERO",
+        }
+    }
+
+    /// Whether the command carries an angle argument.
+    pub fn takes_value(&self) -> bool {
+        matches!(
+            self,
+            FTCommand::SET | FTCommand::SETRAW | FTCommand::SETZERO
+        )
+    }
+
+    /// The kind of reply the filter-tilter sends back for this command: `A` for zero-corrected
+    /// angles, `R` for raw angles and `Z` for the zero point.
+    pub fn expected_result(&self) -> FTCommandResult {
+        match self {
+            FTCommand::GET | FTCommand::SET => FTCommandResult::A,
+            FTCommand::GETRAW | FTCommand::SETRAW => FTCommandResult::R,
+            FTCommand::ZERO | FTCommand::GETZERO | FTCommand::SETZERO => FTCommandResult::Z,
+        }
+    }
+
+    /// Encode the command as it is sent over the serial port, e.g. `SETRAW 171.50\r`. The value
+    /// is ignored for commands that don't take one.
+    pub fn encode(&self, value: f64) -> String {
+        if self.takes_value() {
+            format!("{} {:.2}{}", self.keyword(), value, FT_COMMAND_TERMINATOR)
+        } else {
+            format!("{}{}", self.keyword(), FT_COMMAND_TERMINATOR)
+        }
+    }
+
+    /// Decode a command line as sent by `encode`. Returns `None` if the keyword is unknown, or
+    /// if the argument is missing or malformed for commands that take one. Commands that don't
+    /// take an argument decode with a value of 0.
+    pub fn decode(line: &str) -> Option<(FTCommand, f64)> {
+        let mut fields = line.trim().split_whitespace();
+
+        let command = match fields.next()? {
+            "GET" => FTCommand::GET,
+            "SET" => FTCommand::SET,
+            "GETRAW" => FTCommand::GETRAW,
+            "SETRAW" => FTCommand::SETRAW,
+            "ZERO" => FTCommand::ZERO,
+            "GETZERO" => FTCommand::GETZERO,
+            "SETZERO" => FTCommand::SETZERO,
+            _ => return None,
+        };
+
+        let value = if command.takes_value() {
+            parse::<f64, _>(fields.next()?).ok()?
+        } else {
+            0.
+        };
+
+        if fields.next().is_some() {
+            return None;
+        }
+
+        Some((command, value))
+    }
+}
+
+impl FTCommandResult {
+    /// Parse a single reply line from the filter-tilter. Replies have the form `<tag> <angle>`,
+    /// where the tag is one of `A`, `R` or `Z`. Surrounding whitespace and line terminators are
+    /// ignored.
+    pub fn parse_reply(reply: &str) -> Result<(FTCommandResult, f64)> {
+        let mut fields = reply.trim().split_whitespace();
+
+        let tag = match fields.next() {
+            Some("A") => FTCommandResult::A,
+            Some("R") => FTCommandResult::R,
+            Some("Z") => FTCommandResult::Z,
+            _ => return Err(Error::Protocol(format!("unrecognized reply {:?}", reply))),
+        };
+
+        let value = fields
+            .next()
+            .and_then(|v| parse::<f64, _>(v).ok())
+            .ok_or_else(|| {
+                Error::Protocol(format!("could not parse angle in reply {:?}", reply))
+            })?;
+
+        if fields.next().is_some() {
+            return Err(Error::Protocol(format!(
+                "trailing data in reply {:?}",
+                reply
+            )));
+        }
+
+        Ok((tag, value))
+    }
+}
+
+/// Read a single terminated reply line from the filter-tilter. Carriage returns are dropped, so
+/// both `\n` and `\r\n` line endings are accepted.
+pub fn read_reply<R: Read>(port: &mut R) -> Result<String> {
+    let mut line = Vec::with_capacity(FT_MAX_REPLY_LEN);
+    let mut byte = [0u8; 1];
+
+    loop {
+        match port.read(&mut byte) {
+            Ok(0) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "filter-tilter closed the connection before replying",
+                )))
+            }
+            Ok(_) => match byte[0] {
+                FT_REPLY_TERMINATOR if !line.is_empty() => break,
+                FT_REPLY_TERMINATOR | b'\r' => continue,
+                b => line.push(b),
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        if line.len() > FT_MAX_REPLY_LEN {
+            return Err(Error::Protocol(
+                "reply exceeded the maximum length without a terminator".to_owned(),
+            ));
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&line).to_string())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FTAction<'a> {
     pub command: FTCommand,
     pub value: f64,
     pub portname: &'a str,
     pub simulation: Option<String>,
+    /// Travel limits of the unit; commands that would violate them are refused.
+    pub limits: TravelLimits,
     pub verbose: bool,
 }
 
@@ -53,100 +219,111 @@ pub struct SimulatedFTData {
     pub date: chrono::DateTime<Utc>,
 }
 
-impl<'a> FTAction<'a> {
-    pub fn run(&self) -> Result<(FTCommandResult, f64), Error> {
-        if let Some(path) = &self.simulation {
-            let file = File::open(path);
-            let mut sim = match file {
-                Ok(f) => {
-                    if self.verbose {
-                        println!("Serializing simulated data from {}", path);
-                    }
-                    let res: SimulatedFTData = serde_json::de::from_reader(f)
-                        .expect("Could not deserialize simulated FT data!");
-                    if self.verbose {
-                        println!("Simulated zero point: {}", res.zeropoint);
-                        println!("Simulated raw angle: {}", res.rawangle);
-                    }
-                    res
-                }
-                Err(_) => match File::create(path) {
-                    Ok(f) => {
-                        if self.verbose {
-                            println!("Creating new simulated data file with zero point 0.0 and raw angle 171.0.");
-                        }
-                        let sim = SimulatedFTData {
-                            description: "Simulated position file.".to_owned(),
-                            zeropoint: 0.,
-                            rawangle: 171.,
-                            date: chrono::Utc::now(),
-                        };
-                        let res = serde_json::ser::to_writer(f, &sim);
-                        if res.is_err() {
-                            println!("Could not serialize simulated FT data to file!");
-                        }
-                        sim
-                    }
-                    Err(_) => panic!("Could not read/write file for simulation!"),
-                },
-            };
-
-            let output = match self.command {
-                FTCommand::GET => {
-                    sim.rawangle = self.value + sim.zeropoint;
-                    (FTCommandResult::A, sim.rawangle - sim.zeropoint)
-                }
-                FTCommand::SET => {
-                    sim.rawangle = self.value + sim.zeropoint;
-                    (FTCommandResult::A, self.value)
-                }
-                FTCommand::GETRAW => (FTCommandResult::R, sim.rawangle),
-                FTCommand::SETRAW => {
-                    sim.rawangle = self.value;
-                    (FTCommandResult::R, self.value)
-                }
-                FTCommand::ZERO => {
-                    sim.zeropoint = sim.rawangle;
-                    (FTCommandResult::Z, sim.zeropoint)
-                }
-                FTCommand::GETZERO => (FTCommandResult::Z, sim.zeropoint),
-                FTCommand::SETZERO => {
-                    sim.zeropoint = self.value;
-                    (FTCommandResult::Z, sim.zeropoint)
-                }
-            };
-
-            // if self.verbose {
-            //     println!("Simulation: {:?}", sim);
-            // }
-
-            if let Ok(f) = File::create(path) {
-                if self.verbose {
-                    println!("Serializing output to {}", path);
-                }
-                serde_json::ser::to_writer(f, &sim).expect("Could not serialize output");
+impl SimulatedFTData {
+    /// A fresh simulated unit with zero point 0 and raw angle 171.
+    pub fn new(description: &str) -> Self {
+        SimulatedFTData {
+            description: description.to_owned(),
+            zeropoint: 0.,
+            rawangle: 171.,
+            date: chrono::Utc::now(),
+        }
+    }
+
+    /// Apply a command to the simulated unit as the hardware would, returning its reply.
+    pub fn apply(&mut self, command: FTCommand, value: f64) -> (FTCommandResult, f64) {
+        self.date = chrono::Utc::now();
+
+        match command {
+            FTCommand::GET => (FTCommandResult::A, self.rawangle - self.zeropoint),
+            FTCommand::SET => {
+                self.rawangle = value + self.zeropoint;
+                (FTCommandResult::A, value)
+            }
+            FTCommand::GETRAW => (FTCommandResult::R, self.rawangle),
+            FTCommand::SETRAW => {
+                self.rawangle = value;
+                (FTCommandResult::R, value)
             }
+            FTCommand::ZERO => {
+                self.zeropoint = self.rawangle;
+                (FTCommandResult::Z, self.zeropoint)
+            }
+            FTCommand::GETZERO => (FTCommandResult::Z, self.zeropoint),
+            FTCommand::SETZERO => {
+                self.zeropoint = value;
+                (FTCommandResult::Z, self.zeropoint)
+            }
+        }
+    }
+}
 
-            Ok(output)
-        } else {
-            let mut port = serialport::new(self.portname, 9600)
-                .parity(serialport::Parity::None)
-                .data_bits(serialport::DataBits::Eight)
-                .stop_bits(serialport::StopBits::One)
-                .timeout(Duration::from_millis(5000))
-                .open_native()
-                .expect(format!("Could not open serial port {}", self.portname).as_str());
-
-            port.clear(serialport::ClearBuffer::All)
-                .expect("Could not clear serial port input/output buffers!");
-            port.write_fmt(format_args!("{} {}", stringify!(self.command), self.value))
-                .expect("Could not write to serial port!");
-
-            let mut output = "".to_owned();
-            port.read_to_string(&mut output)
-                .expect("Could not read from serial port!");
-
-            todo!()
+impl<'a> FTAction<'a> {
+    /// Run a single command, opening (and closing) the port or simulation file just for it. Use
+    /// a `FilterTilter` to issue several commands over one connection.
+    pub fn run(&self) -> Result<(FTCommandResult, f64)> {
+        let mut tilter = match &self.simulation {
+            Some(path) => FilterTilter::simulation(path, self.verbose)?,
+            None => FilterTilter::serial(self.portname, self.verbose),
         }
+        .limits(self.limits);
+        tilter.execute(self.command, self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_commands() {
+        assert_eq!(FTCommand::GET.encode(12.), "GET\r");
+        assert_eq!(FTCommand::GETRAW.encode(0.), "GETRAW\r");
+        assert_eq!(FTCommand::SET.encode(10.), "SET 10.00\r");
+        assert_eq!(FTCommand::SETRAW.encode(171.456), "SETRAW 171.46\r");
+        assert_eq!(FTCommand::ZERO.encode(3.), "ZERO\r");
+        assert_eq!(FTCommand::SETZERO.encode(-1.5), "SETZERO -1.50\r");
+    }
+
+    #[test]
+    fn test_decode_commands() {
+        for &(command, value) in &[
+            (FTCommand::GET, 0.),
+            (FTCommand::SETRAW, 171.5),
+            (FTCommand::SETZERO, -1.25),
+        ] {
+            let (decoded, decoded_value) = FTCommand::decode(&command.encode(value)).unwrap();
+            assert_eq!(decoded.keyword(), command.keyword());
+            assert_eq!(decoded_value, value);
+        }
+
+        assert!(FTCommand::decode("SET").is_none());
+        assert!(FTCommand::decode("SETRAW abc").is_none());
+        assert!(FTCommand::decode("MOVE 1.0").is_none());
+        assert!(FTCommand::decode("GET 1.0").is_none());
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let (tag, value) = FTCommandResult::parse_reply("A 10.00\r\n").unwrap();
+        assert_eq!(tag, FTCommandResult::A);
+        assert_eq!(value, 10.);
+
+        let (tag, value) = FTCommandResult::parse_reply("R 171.5").unwrap();
+        assert_eq!(tag, FTCommandResult::R);
+        assert_eq!(value, 171.5);
+
+        assert!(FTCommandResult::parse_reply("Q 1.0").is_err());
+        assert!(FTCommandResult::parse_reply("Z").is_err());
+        assert!(FTCommandResult::parse_reply("Z abc").is_err());
+        assert!(FTCommandResult::parse_reply("Z 1.0 2.0").is_err());
+    }
+
+    #[test]
+    fn test_read_reply() {
+        let mut input: &[u8] = b"\r\nR 171.00\r\nZ 0.00\r\n";
+        assert_eq!(read_reply(&mut input).unwrap(), "R 171.00");
+        assert_eq!(read_reply(&mut input).unwrap(), "Z 0.00");
+        assert!(read_reply(&mut input).is_err());
     }
 }
@@ -0,0 +1,304 @@
+//! A virtual filter-tilter that speaks the serial protocol over a pseudo-terminal, so that the
+//! `serialport` path of `FTAction::run` can be exercised without a unit on the bench.
+
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serialport::{SerialPort, TTYPort};
+
+use super::data_collection::{FTCommand, FTCommandResult, SimulatedFTData, FT_REPLY_TERMINATOR};
+
+/// How often the emulator wakes up to check whether it has been asked to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Behaviour of the virtual filter-tilter, including any faults to inject.
+#[derive(Debug, Clone)]
+pub struct EmulatorConfig {
+    /// Zero point the unit starts with.
+    pub zeropoint: f64,
+    /// Raw angle the unit starts at.
+    pub rawangle: f64,
+    /// Delay before each reply is sent.
+    pub latency: Duration,
+    /// Probability in [0, 1] that a reply is garbled on the wire.
+    pub garble_probability: f64,
+    /// Hang up the connection after answering this many commands.
+    pub disconnect_after: Option<usize>,
+    /// How fast the filter moves, in degrees per second. Moves are instantaneous if `None`.
+    pub slew_rate: Option<f64>,
+    /// Whether to print every command and reply.
+    pub verbose: bool,
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        EmulatorConfig {
+            zeropoint: 0.,
+            rawangle: 171.,
+            latency: Duration::from_millis(0),
+            garble_probability: 0.,
+            disconnect_after: None,
+            slew_rate: None,
+            verbose: false,
+        }
+    }
+}
+
+/// Apply a single command line to the emulated unit and return the reply line, or `None` if
+/// the command is not understood (the real unit stays silent in that case).
+pub fn respond(state: &mut SimulatedFTData, line: &str) -> Option<String> {
+    let (command, value) = FTCommand::decode(line)?;
+    let (tag, value) = state.apply(command, value);
+    Some(format_reply(tag, value))
+}
+
+fn format_reply(tag: FTCommandResult, value: f64) -> String {
+    format!("{:?} {:.2}\r\n", tag, value)
+}
+
+/// Corrupt a reply the way a noisy line would: mangle the tag, flip a few more bytes and drop
+/// the terminator half of the time.
+fn garble(reply: &str) -> Vec<u8> {
+    let mut bytes = reply.trim_end().as_bytes().to_vec();
+    for (i, b) in bytes.iter_mut().enumerate() {
+        if i == 0 {
+            *b = (alea::u32() % 26) as u8 + b'a';
+        } else if alea::f64() < 0.5 {
+            *b = (alea::u32() % 94) as u8 + b'!';
+        }
+    }
+    if alea::f64() < 0.5 {
+        bytes.push(FT_REPLY_TERMINATOR);
+    }
+    bytes
+}
+
+/// A virtual filter-tilter attached to the master side of a pseudo-terminal. Clients connect
+/// to the slave side by name, exactly as they would to a real USB serial port.
+pub struct FTEmulator {
+    master: TTYPort,
+    port_name: String,
+    config: EmulatorConfig,
+    state: Arc<Mutex<SimulatedFTData>>,
+    /// Where the last move started from, and when.
+    motion: Option<(f64, Instant)>,
+}
+
+impl FTEmulator {
+    pub fn new(config: EmulatorConfig) -> serialport::Result<Self> {
+        let (mut master, slave) = TTYPort::pair()?;
+        let port_name = slave.name().ok_or_else(|| {
+            serialport::Error::new(
+                serialport::ErrorKind::NoDevice,
+                "Could not determine the name of the pseudo-terminal.",
+            )
+        })?;
+        // Release the slave so that clients can open it exclusively.
+        let mut slave = slave;
+        slave.set_exclusive(false)?;
+        drop(slave);
+        master.set_timeout(POLL_INTERVAL)?;
+
+        let mut state = SimulatedFTData::new("Emulated filter-tilter.");
+        state.zeropoint = config.zeropoint;
+        state.rawangle = config.rawangle;
+
+        Ok(FTEmulator {
+            master,
+            port_name,
+            config,
+            state: Arc::new(Mutex::new(state)),
+            motion: None,
+        })
+    }
+
+    /// Path of the serial port that clients should open.
+    pub fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    /// Shared handle on the emulated unit's zero point and raw angle.
+    pub fn state(&self) -> Arc<Mutex<SimulatedFTData>> {
+        Arc::clone(&self.state)
+    }
+
+    /// Raw angle the filter is physically at, given the raw angle it is heading for.
+    fn position(&self, target: f64) -> f64 {
+        match (self.motion, self.config.slew_rate) {
+            (Some((from, started)), Some(rate)) => {
+                let travelled = rate * started.elapsed().as_secs_f64();
+                if travelled >= (target - from).abs() {
+                    target
+                } else {
+                    from + travelled * (target - from).signum()
+                }
+            }
+            _ => target,
+        }
+    }
+
+    /// Like `respond`, but with moves taking time according to the configured slew rate.
+    fn reply_to(&mut self, line: &str) -> Option<String> {
+        let (command, value) = FTCommand::decode(line)?;
+        let state = Arc::clone(&self.state);
+        let mut state = state.lock().unwrap();
+
+        let position = self.position(state.rawangle);
+        let (tag, value) = match state.apply(command, value) {
+            (tag, _) if matches!(command, FTCommand::GETRAW) => (tag, position),
+            (tag, _) if matches!(command, FTCommand::GET) => (tag, position - state.zeropoint),
+            output => output,
+        };
+        if matches!(command, FTCommand::SET | FTCommand::SETRAW) {
+            self.motion = Some((position, Instant::now()));
+        }
+
+        Some(format_reply(tag, value))
+    }
+
+    /// Serve commands until `stop` is set or the configured disconnect is triggered.
+    pub fn run(mut self, stop: &AtomicBool) -> io::Result<()> {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        let mut nanswered = 0;
+
+        while !stop.load(Ordering::Relaxed) {
+            match self.master.read(&mut byte) {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Reading the master fails while no client has the slave open.
+                Err(_) => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            }
+
+            if byte[0] != b'\r' && byte[0] != b'\n' {
+                line.push(byte[0]);
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+
+            let command = String::from_utf8_lossy(&line).to_string();
+            line.clear();
+
+            let reply = self.reply_to(&command);
+            if self.config.verbose {
+                println!("Received {:?}, replying {:?}", command, reply);
+            }
+
+            if let Some(reply) = reply {
+                thread::sleep(self.config.latency);
+                if alea::f64() < self.config.garble_probability {
+                    self.master.write_all(&garble(&reply))?;
+                } else {
+                    self.master.write_all(reply.as_bytes())?;
+                }
+                self.master.flush()?;
+            }
+
+            nanswered += 1;
+            if Some(nanswered) == self.config.disconnect_after {
+                if self.config.verbose {
+                    println!("Disconnecting after {} commands.", nanswered);
+                }
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the emulator on a background thread.
+    pub fn spawn(self) -> EmulatorHandle {
+        let port_name = self.port_name.clone();
+        let state = self.state();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || self.run(&thread_stop));
+
+        EmulatorHandle {
+            port_name,
+            state,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// A running emulator. The emulator is stopped and the pseudo-terminal closed when the handle
+/// is dropped.
+pub struct EmulatorHandle {
+    port_name: String,
+    state: Arc<Mutex<SimulatedFTData>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl EmulatorHandle {
+    /// Path of the serial port that clients should open.
+    pub fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    /// Snapshot of the emulated unit's zero point and raw angle.
+    pub fn state(&self) -> SimulatedFTData {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Stop the emulator and wait for it to hang up.
+    pub fn stop(mut self) -> io::Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Filter-tilter emulator thread panicked.",
+                ))
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for EmulatorHandle {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_respond() {
+        let mut s = SimulatedFTData::new("");
+
+        assert_eq!(respond(&mut s, "GETRAW").unwrap(), "R 171.00\r\n");
+        assert_eq!(respond(&mut s, "SETRAW 180.5").unwrap(), "R 180.50\r\n");
+        assert_eq!(respond(&mut s, "ZERO").unwrap(), "Z 180.50\r\n");
+        assert_eq!(respond(&mut s, "GET").unwrap(), "A 0.00\r\n");
+        assert_eq!(respond(&mut s, "SET -2").unwrap(), "A -2.00\r\n");
+        assert_eq!(s.rawangle, 178.5);
+        assert_eq!(respond(&mut s, "SETZERO 180").unwrap(), "Z 180.00\r\n");
+        assert_eq!(respond(&mut s, "GET").unwrap(), "A -1.50\r\n");
+        assert!(respond(&mut s, "SETRAW").is_none());
+        assert!(respond(&mut s, "HELLO").is_none());
+    }
+}
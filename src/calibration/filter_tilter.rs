@@ -0,0 +1,493 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use super::data_collection::{
+    read_reply, FTCommand, FTCommandResult, SimulatedFTData, FT_BAUD_RATE, FT_TIMEOUT,
+};
+use crate::error::{Error, Result};
+
+/// Number of times a failed command is retried (reconnecting in between) before giving up.
+const DEFAULT_RETRIES: usize = 2;
+/// Number of consecutive in-tolerance reads required before a move is considered settled.
+const DEFAULT_SETTLE_READS: usize = 3;
+/// Time between position reads while waiting for a move to settle.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Mechanical travel limits of a filter-tilter unit, in raw degrees. Commands that would drive
+/// the filter outside them are refused before they are sent.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TravelLimits {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl TravelLimits {
+    /// Fails with `Error::InvalidLimits` unless `min` is below `max`.
+    pub fn new(min: f64, max: f64) -> Result<Self> {
+        if min < max {
+            Ok(TravelLimits { min, max })
+        } else {
+            Err(Error::InvalidLimits { min, max })
+        }
+    }
+
+    /// Whether a raw angle is within the limits (inclusive).
+    pub fn contains(&self, raw_angle: f64) -> bool {
+        raw_angle >= self.min && raw_angle <= self.max
+    }
+}
+
+impl Default for TravelLimits {
+    fn default() -> Self {
+        TravelLimits {
+            min: 160.,
+            max: 200.,
+        }
+    }
+}
+
+/// Where the filter actually ended up after a `move_to`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MoveResult {
+    /// The requested raw angle.
+    pub target: f64,
+    /// The raw angle reported by the unit once settled.
+    pub raw_angle: f64,
+    /// The settled angle relative to the unit's zero point.
+    pub angle: f64,
+}
+
+enum Backend {
+    Serial {
+        portname: String,
+        port: Option<Box<dyn SerialPort>>,
+    },
+    Simulation {
+        path: PathBuf,
+        state: SimulatedFTData,
+    },
+}
+
+/// A handle on a filter-tilter unit. For a real unit the serial port is opened on the first
+/// command and kept open for every command after it; if a command fails, the port is closed,
+/// reopened and the command retried. In simulation the state lives in a JSON file, which is
+/// rewritten after every command.
+///
+/// Every command is checked against the unit's `TravelLimits` first: moves outside them are
+/// refused, as are zero points that would put angle 0 outside them.
+pub struct FilterTilter {
+    backend: Backend,
+    limits: TravelLimits,
+    retries: usize,
+    settle_reads: usize,
+    poll_interval: Duration,
+    verbose: bool,
+}
+
+impl FilterTilter {
+    /// A filter-tilter on the given serial port. The port is not opened until it is needed.
+    pub fn serial(portname: &str, verbose: bool) -> Self {
+        FilterTilter {
+            backend: Backend::Serial {
+                portname: portname.to_owned(),
+                port: None,
+            },
+            limits: TravelLimits::default(),
+            retries: DEFAULT_RETRIES,
+            settle_reads: DEFAULT_SETTLE_READS,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            verbose,
+        }
+    }
+
+    /// A simulated filter-tilter whose state is kept in the JSON file at `path`. The file is
+    /// created with zero point 0 and raw angle 171 if it does not exist yet.
+    pub fn simulation<P: AsRef<Path>>(path: P, verbose: bool) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let state = match File::open(&path) {
+            Ok(f) => {
+                if verbose {
+                    println!("Serializing simulated data from {}", path.display());
+                }
+                let state: SimulatedFTData = serde_json::de::from_reader(f).map_err(|e| {
+                    Error::Simulation(format!(
+                        "could not deserialize simulated FT data from {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+                if verbose {
+                    println!("Simulated zero point: {}", state.zeropoint);
+                    println!("Simulated raw angle: {}", state.rawangle);
+                }
+                state
+            }
+            Err(_) => {
+                if verbose {
+                    println!(
+                        "Creating new simulated data file with zero point 0.0 and raw angle 171.0."
+                    );
+                }
+                SimulatedFTData::new("Simulated position file.")
+            }
+        };
+
+        let tilter = FilterTilter {
+            backend: Backend::Simulation { path, state },
+            limits: TravelLimits::default(),
+            retries: DEFAULT_RETRIES,
+            settle_reads: DEFAULT_SETTLE_READS,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            verbose,
+        };
+        tilter.save_simulation()?;
+
+        Ok(tilter)
+    }
+
+    /// Set the unit's mechanical travel limits.
+    pub fn limits(mut self, limits: TravelLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The unit's mechanical travel limits.
+    pub fn travel_limits(&self) -> TravelLimits {
+        self.limits
+    }
+
+    /// Set how many times a failed command is retried before giving up.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set how many consecutive in-tolerance reads `move_to` needs before it considers a move
+    /// settled.
+    pub fn settle_reads(mut self, settle_reads: usize) -> Self {
+        self.settle_reads = settle_reads.max(1);
+        self
+    }
+
+    /// Set the time between position reads in `move_to`.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Send a command and return the unit's reply. Fails with `Error::Interlock`, without
+    /// sending anything, if the command would violate the travel limits.
+    pub fn execute(&mut self, command: FTCommand, value: f64) -> Result<(FTCommandResult, f64)> {
+        self.check_interlocks(command, value)?;
+        self.send(command, value)
+    }
+
+    /// Check that a command keeps the filter within its travel limits.
+    fn check_interlocks(&mut self, command: FTCommand, value: f64) -> Result<()> {
+        let limits = self.limits;
+        let refuse = |what: String| -> Result<()> {
+            Err(Error::Interlock(format!(
+                "{} is outside the travel limits [{}, {}]",
+                what, limits.min, limits.max
+            )))
+        };
+
+        match command {
+            FTCommand::SETRAW if !limits.contains(value) => refuse(format!("Raw angle {}", value)),
+            FTCommand::SET => {
+                let zeropoint = self.send(FTCommand::GETZERO, 0.)?.1;
+                if limits.contains(value + zeropoint) {
+                    Ok(())
+                } else {
+                    refuse(format!(
+                        "Angle {} (raw angle {} with zero point {})",
+                        value,
+                        value + zeropoint,
+                        zeropoint
+                    ))
+                }
+            }
+            FTCommand::SETZERO if !limits.contains(value) => {
+                refuse(format!("Zero point {}", value))
+            }
+            FTCommand::ZERO => {
+                let rawangle = self.send(FTCommand::GETRAW, 0.)?.1;
+                if limits.contains(rawangle) {
+                    Ok(())
+                } else {
+                    refuse(format!("Zero point {}", rawangle))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Send a command without any interlock checks.
+    fn send(&mut self, command: FTCommand, value: f64) -> Result<(FTCommandResult, f64)> {
+        if let Backend::Simulation { state, .. } = &mut self.backend {
+            let output = state.apply(command, value);
+            self.save_simulation()?;
+            return Ok(output);
+        }
+
+        let mut attempt = 0;
+        loop {
+            match self.transact(command, value) {
+                Ok(output) => return Ok(output),
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    if self.verbose {
+                        println!(
+                            "{:?} failed ({}), reconnecting (attempt {} of {}).",
+                            command, e, attempt, self.retries
+                        );
+                    }
+                    self.disconnect();
+                }
+                Err(e) => {
+                    self.disconnect();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Zero-corrected angle of the filter.
+    pub fn get_angle(&mut self) -> Result<f64> {
+        Ok(self.execute(FTCommand::GET, 0.)?.1)
+    }
+
+    /// Move the filter to a zero-corrected angle, returning the angle reported by the unit.
+    pub fn set_angle(&mut self, angle: f64) -> Result<f64> {
+        Ok(self.execute(FTCommand::SET, angle)?.1)
+    }
+
+    /// Raw (uncorrected) angle of the filter.
+    pub fn get_raw_angle(&mut self) -> Result<f64> {
+        Ok(self.execute(FTCommand::GETRAW, 0.)?.1)
+    }
+
+    /// Move the filter to a raw angle, returning the angle reported by the unit.
+    pub fn set_raw_angle(&mut self, angle: f64) -> Result<f64> {
+        Ok(self.execute(FTCommand::SETRAW, angle)?.1)
+    }
+
+    /// Make the current raw angle the zero point, returning the new zero point.
+    pub fn zero(&mut self) -> Result<f64> {
+        Ok(self.execute(FTCommand::ZERO, 0.)?.1)
+    }
+
+    /// Raw angle corresponding to zero tilt.
+    pub fn get_zero(&mut self) -> Result<f64> {
+        Ok(self.execute(FTCommand::GETZERO, 0.)?.1)
+    }
+
+    /// Set the raw angle corresponding to zero tilt, returning the new zero point.
+    pub fn set_zero(&mut self, zeropoint: f64) -> Result<f64> {
+        Ok(self.execute(FTCommand::SETZERO, zeropoint)?.1)
+    }
+
+    /// Move the filter to a raw angle and wait for it to get there. The position is polled until
+    /// it is within `tolerance` degrees of the target, and has changed by no more than
+    /// `tolerance` between reads, for `settle_reads` consecutive reads. Fails with
+    /// `Error::NotSettled` if that doesn't happen within `timeout`, after trying to stop the
+    /// filter.
+    pub fn move_to(
+        &mut self,
+        raw_angle: f64,
+        tolerance: f64,
+        timeout: Duration,
+    ) -> Result<MoveResult> {
+        let start = Instant::now();
+        self.set_raw_angle(raw_angle)?;
+
+        let mut last = self.get_raw_angle()?;
+        let mut nstable = if (last - raw_angle).abs() <= tolerance {
+            1
+        } else {
+            0
+        };
+
+        while nstable < self.settle_reads {
+            if start.elapsed() > timeout {
+                // Don't leave the filter driving towards a position it can't reach.
+                let stop_error = self.emergency_stop().err().map(|e| e.to_string());
+                return Err(Error::NotSettled {
+                    target: raw_angle,
+                    reported: last,
+                    stop_error,
+                });
+            }
+            thread::sleep(self.poll_interval);
+
+            let current = self.get_raw_angle()?;
+            if (current - raw_angle).abs() <= tolerance && (current - last).abs() <= tolerance {
+                nstable += 1;
+            } else {
+                nstable = 0;
+            }
+            last = current;
+        }
+
+        let zeropoint = self.get_zero()?;
+        if self.verbose {
+            println!(
+                "Settled at raw angle {} (target {}, zero point {})",
+                last, raw_angle, zeropoint
+            );
+        }
+
+        Ok(MoveResult {
+            target: raw_angle,
+            raw_angle: last,
+            angle: last - zeropoint,
+        })
+    }
+
+    /// Halt the filter wherever it is, returning the raw angle it stopped at. The controller has
+    /// no stop command, so this reads the current raw angle and sends the filter there. This is
+    /// never refused by the interlocks.
+    pub fn emergency_stop(&mut self) -> Result<f64> {
+        if self.verbose {
+            println!("Emergency stop!");
+        }
+        let here = self.send(FTCommand::GETRAW, 0.)?.1;
+        Ok(self.send(FTCommand::SETRAW, here)?.1)
+    }
+
+    /// Close the serial port, if open. It is reopened on the next command.
+    pub fn disconnect(&mut self) {
+        if let Backend::Serial { port, .. } = &mut self.backend {
+            *port = None;
+        }
+    }
+
+    fn transact(&mut self, command: FTCommand, value: f64) -> Result<(FTCommandResult, f64)> {
+        let verbose = self.verbose;
+        let (portname, port) = match &mut self.backend {
+            Backend::Serial { portname, port } => (portname, port),
+            Backend::Simulation { .. } => unreachable!(),
+        };
+
+        if port.is_none() {
+            if verbose {
+                println!("Opening serial port {}", portname);
+            }
+            let mut opened = serialport::new(portname.as_str(), FT_BAUD_RATE)
+                .parity(serialport::Parity::None)
+                .data_bits(serialport::DataBits::Eight)
+                .stop_bits(serialport::StopBits::One)
+                .timeout(FT_TIMEOUT)
+                .open()?;
+            opened.clear(serialport::ClearBuffer::All)?;
+            *port = Some(opened);
+        }
+        let port = port.as_mut().unwrap();
+
+        let encoded = command.encode(value);
+        if verbose {
+            println!("Sending {:?} to {}", encoded, portname);
+        }
+
+        port.write_all(encoded.as_bytes())?;
+        port.flush()?;
+
+        let reply = read_reply(port)?;
+        if verbose {
+            println!("Received {:?} from {}", reply, portname);
+        }
+
+        let (tag, value) = FTCommandResult::parse_reply(&reply)?;
+        if tag != command.expected_result() {
+            return Err(Error::Protocol(format!(
+                "expected a {:?} reply to {:?}, got {:?}",
+                command.expected_result(),
+                command,
+                reply
+            )));
+        }
+
+        Ok((tag, value))
+    }
+
+    fn save_simulation(&self) -> Result<()> {
+        if let Backend::Simulation { path, state } = &self.backend {
+            if self.verbose {
+                println!("Serializing output to {}", path.display());
+            }
+            let f = File::create(path).map_err(|e| {
+                Error::Simulation(format!(
+                    "could not write simulation file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            serde_json::ser::to_writer(f, state).map_err(|e| {
+                Error::Simulation(format!("could not serialize simulated FT data: {}", e))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    /// A simulated tilter keeping its state in a temporary directory, which must outlive it.
+    fn simulated_tilter(name: &str) -> (TempDir, FilterTilter) {
+        let dir = TempDir::new(name);
+        let tilter = FilterTilter::simulation(dir.path().join("state.json"), false).unwrap();
+        (dir, tilter)
+    }
+
+    #[test]
+    fn test_interlocks() {
+        let (_dir, tilter) = simulated_tilter("ft-interlocks");
+        let mut tilter = tilter.limits(TravelLimits::new(165., 195.).unwrap());
+
+        assert_eq!(tilter.set_raw_angle(190.).unwrap(), 190.);
+        assert!(matches!(
+            tilter.set_raw_angle(196.),
+            Err(Error::Interlock(_))
+        ));
+        assert!(matches!(
+            tilter.set_raw_angle(150.),
+            Err(Error::Interlock(_))
+        ));
+        assert_eq!(tilter.get_raw_angle().unwrap(), 190.);
+
+        assert_eq!(tilter.set_zero(180.).unwrap(), 180.);
+        assert_eq!(tilter.set_angle(-10.).unwrap(), -10.);
+        assert!(matches!(tilter.set_angle(20.), Err(Error::Interlock(_))));
+        assert!(matches!(tilter.set_zero(200.), Err(Error::Interlock(_))));
+        assert_eq!(tilter.get_zero().unwrap(), 180.);
+
+        assert_eq!(tilter.emergency_stop().unwrap(), 170.);
+        assert!(matches!(
+            TravelLimits::new(195., 165.),
+            Err(Error::InvalidLimits { .. })
+        ));
+    }
+
+    #[test]
+    fn test_zero_outside_limits_is_refused() {
+        let (_dir, tilter) = simulated_tilter("ft-zero");
+        let mut tilter = tilter.limits(TravelLimits::new(172., 195.).unwrap());
+
+        // The simulated unit starts at a raw angle of 171.
+        assert!(matches!(tilter.zero(), Err(Error::Interlock(_))));
+        tilter.set_raw_angle(180.).unwrap();
+        assert_eq!(tilter.zero().unwrap(), 180.);
+    }
+}
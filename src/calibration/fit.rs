@@ -0,0 +1,436 @@
+use compute::prelude::{interp1d_linear_unchecked, linspace, ExtrapolationMode};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    model::{TransmissionCurves, TransmissionModel},
+    transmission::FilterSpec,
+};
+use crate::{
+    error::{Error, Result},
+    optimize::nelder_mead,
+    utils::{cmp_nan_last, quantile},
+};
+
+/// Best-fit parameters of the tilt calibration.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TiltFit {
+    /// Strength of the [NII] line relative to Hα.
+    pub nii_fraction: f64,
+    /// Tilt, in degrees, of the filter at a raw angle of 180.
+    pub tilt_shift: f64,
+    /// Weighted sum of squared residuals at the best fit.
+    pub residual: f64,
+}
+
+/// Filter and laser properties that can optionally be fitted alongside the NII fraction and
+/// tilt shift, rather than held at their nominal values.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct FreeParameters {
+    /// Fit the central wavelength of the filter at normal incidence.
+    pub filter_cwl: bool,
+    /// Fit the effective refractive index of the filter.
+    pub effective_index: bool,
+    /// Fit the FWHM of the laser lines.
+    pub laser_fwhm: bool,
+}
+
+impl FreeParameters {
+    /// Whether any filter or laser property is freed.
+    pub fn any(&self) -> bool {
+        self.filter_cwl || self.effective_index || self.laser_fwhm
+    }
+}
+
+/// Best-fit parameters of a calibration fit that also characterizes the filter.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterFit {
+    /// NII fraction, tilt shift and residual at the best fit.
+    pub tilt: TiltFit,
+    /// The transmission model with any freed properties set to their best-fit values.
+    pub model: TransmissionModel,
+}
+
+/// Initial optimizer steps for the filter CWL (nm), effective index and laser FWHM (nm).
+const FILTER_STEPS: [f64; 3] = [0.2, 0.05, 0.05];
+/// How far, in nm, the filter CWL may stray from its nominal value.
+const CWL_RANGE: f64 = 5.;
+
+/// Number of grid points in NII fraction and tilt shift used to seed the optimizer.
+const SEED_FRACTIONS: usize = 11;
+const SEED_SHIFTS: usize = 101;
+/// Number of best grid points the optimizer is started from.
+const NSEEDS: usize = 3;
+/// Range of tilt shifts, in degrees, searched by the seed grid.
+const SHIFT_RANGE: f64 = 25.;
+
+/// Model spot flux at the given tilts (in degrees, relative to the shifted zero point),
+/// normalized to a peak of 1.
+pub fn tilt_model(curves: &TransmissionCurves, nii_fraction: f64, tilts: &[f64]) -> Vec<f64> {
+    let totalflux = curves.total_flux(nii_fraction);
+    let max = totalflux.iter().cloned().fold(f64::MIN, f64::max);
+    let totalfluxnorm = totalflux.iter().map(|x| x / max).collect::<Vec<_>>();
+
+    interp1d_linear_unchecked(
+        &curves.tilt,
+        &totalfluxnorm,
+        tilts,
+        ExtrapolationMode::Fill(0., 0.),
+    )
+    .to_vec()
+}
+
+/// Flux uncertainties as the fit uses them. An uncertainty that was not measured, i.e. is not
+/// finite or not positive (e.g. at an angle where only one frame was usable, which has no
+/// scatter), is replaced by the median of the measured ones. Returns the uncertainties and how
+/// many of them were replaced, or `None` if none was measured.
+pub fn fill_errors(dataerr: &[f64]) -> Option<(Vec<f64>, usize)> {
+    let is_measured = |e: f64| e.is_finite() && e > 0.;
+    let mut measured = dataerr
+        .iter()
+        .cloned()
+        .filter(|&e| is_measured(e))
+        .collect::<Vec<_>>();
+    if measured.is_empty() {
+        return None;
+    }
+    measured.sort_by(cmp_nan_last);
+    let median = quantile(&measured, 0.5);
+    let errors = dataerr
+        .iter()
+        .map(|&e| if is_measured(e) { e } else { median })
+        .collect();
+    Some((errors, dataerr.len() - measured.len()))
+}
+
+/// Square roots of the weights given to each measurement by the fit. With flux uncertainties,
+/// these are the inverse uncertainties of the normalized fluxes after `fill_errors`, so that
+/// the weighted residual is a chi-square. Without them, or if none was measured, points are
+/// weighted by `1 + flux`, favouring the peak.
+pub fn fit_weights(dataflux: &[f64], dataerr: Option<&[f64]>) -> Vec<f64> {
+    match dataerr.and_then(fill_errors) {
+        Some((err, _)) => {
+            let max = dataflux.iter().cloned().fold(f64::MIN, f64::max);
+            err.iter().map(|e| max / e).collect()
+        }
+        None => normalize_flux(dataflux)
+            .iter()
+            .map(|d| (1. + d).sqrt())
+            .collect(),
+    }
+}
+
+/// Weighted residuals between normalized measured fluxes and the model with the given NII
+/// fraction and tilt shift, one per measurement, each scaled by its weight from `fit_weights`.
+pub fn tilt_residuals(
+    curves: &TransmissionCurves,
+    datatilt: &[f64],
+    datafluxnorm: &[f64],
+    weights: &[f64],
+    nii_fraction: f64,
+    shift: f64,
+) -> Vec<f64> {
+    let shifted = datatilt.iter().map(|t| t - shift).collect::<Vec<_>>();
+    tilt_model(curves, nii_fraction, &shifted)
+        .iter()
+        .zip(datafluxnorm)
+        .zip(weights)
+        .map(|((m, d), w)| (m - d) * w)
+        .collect()
+}
+
+/// Weighted sum of squared residuals; see `tilt_residuals`.
+pub fn tilt_residual(
+    curves: &TransmissionCurves,
+    datatilt: &[f64],
+    datafluxnorm: &[f64],
+    weights: &[f64],
+    nii_fraction: f64,
+    shift: f64,
+) -> f64 {
+    tilt_residuals(curves, datatilt, datafluxnorm, weights, nii_fraction, shift)
+        .iter()
+        .map(|r| r * r)
+        .sum()
+}
+
+/// Normalize fluxes to a peak of 1.
+pub fn normalize_flux(dataflux: &[f64]) -> Vec<f64> {
+    let max = dataflux.iter().cloned().fold(f64::MIN, f64::max);
+    dataflux.iter().map(|x| x / max).collect()
+}
+
+/// Check that there is something to fit: one finite flux per tilt, at least one of them
+/// positive. Without a positive peak the fluxes cannot be normalized.
+pub fn check_fit_data(datatilt: &[f64], dataflux: &[f64]) -> Result<()> {
+    if datatilt.len() != dataflux.len() {
+        return Err(Error::Fit(format!(
+            "{} tilts but {} fluxes",
+            datatilt.len(),
+            dataflux.len()
+        )));
+    }
+    if let Some(bad) = dataflux.iter().find(|f| !f.is_finite()) {
+        return Err(Error::Fit(format!("spot flux {} is not finite", bad)));
+    }
+    if !dataflux.iter().any(|&f| f > 0.) {
+        return Err(Error::Fit(
+            "no positive spot flux was measured; is the laser on and the spot in the frame?"
+                .to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Fit the NII fraction and tilt shift to spot fluxes measured at the given tilts (raw angle
+/// minus 180), weighted as `fit_weights` describes given the flux uncertainties `dataerr`. A
+/// coarse grid locates the basin of the minimum, then Nelder-Mead refines the
+/// best few grid points to full precision. The NII fraction is constrained to [0, 1]. Fails if
+/// `check_fit_data` rejects the data.
+pub fn fit_tilt_calibration(
+    curves: &TransmissionCurves,
+    datatilt: &[f64],
+    dataflux: &[f64],
+    dataerr: Option<&[f64]>,
+) -> Result<TiltFit> {
+    check_fit_data(datatilt, dataflux)?;
+    if let Some(err) = dataerr.filter(|err| err.len() != dataflux.len()) {
+        return Err(Error::Fit(format!(
+            "{} fluxes but {} flux uncertainties",
+            dataflux.len(),
+            err.len()
+        )));
+    }
+    let datafluxnorm = normalize_flux(dataflux);
+    let weights = fit_weights(dataflux, dataerr);
+    let objective = |p: &[f64]| {
+        if p[0] < 0. || p[0] > 1. {
+            f64::INFINITY
+        } else {
+            tilt_residual(curves, datatilt, &datafluxnorm, &weights, p[0], p[1])
+        }
+    };
+
+    let mut grid = linspace(0., 1., SEED_FRACTIONS)
+        .iter()
+        .flat_map(|&frac| {
+            linspace(-SHIFT_RANGE, SHIFT_RANGE, SEED_SHIFTS)
+                .iter()
+                .map(|&shift| (frac, shift))
+                .collect::<Vec<_>>()
+        })
+        .map(|(frac, shift)| (frac, shift, objective(&[frac, shift])))
+        .collect::<Vec<_>>();
+    grid.sort_by(|a, b| cmp_nan_last(&a.2, &b.2));
+
+    let shift_step = 2. * SHIFT_RANGE / (SEED_SHIFTS - 1) as f64;
+    grid.iter()
+        .take(NSEEDS)
+        .map(|&(frac, shift, _)| {
+            nelder_mead(
+                &objective,
+                &[frac, shift],
+                &[if frac > 0.5 { -0.05 } else { 0.05 }, shift_step],
+                1e-10,
+                5000,
+            )
+        })
+        .min_by(|a, b| cmp_nan_last(&a.fx, &b.fx))
+        .map(|min| TiltFit {
+            nii_fraction: min.x[0],
+            tilt_shift: min.x[1],
+            residual: min.fx,
+        })
+        .ok_or_else(|| Error::Fit("no seed points for the optimizer".to_owned()))
+}
+
+/// Fit the NII fraction and tilt shift as `fit_tilt_calibration` does, additionally freeing
+/// the filter and laser properties selected in `free`. The model curves are recomputed at every
+/// step, so this is much slower than the fixed-model fit, which is used to seed it. `model`
+/// supplies the nominal values of the freed properties and the tilt grid. Fails if the model
+/// cannot be computed or the seed fit fails.
+pub fn fit_filter_calibration(
+    model: &TransmissionModel,
+    free: FreeParameters,
+    datatilt: &[f64],
+    dataflux: &[f64],
+    dataerr: Option<&[f64]>,
+) -> Result<FilterFit> {
+    let seed = fit_tilt_calibration(&model.compute()?, datatilt, dataflux, dataerr)?;
+    if !free.any() {
+        return Ok(FilterFit {
+            tilt: seed,
+            model: model.clone(),
+        });
+    }
+
+    let freed = [free.filter_cwl, free.effective_index, free.laser_fwhm];
+    let nominal = [model.spec.cwl, model.spec.effective_index, model.laser_fwhm];
+
+    // Parameters are the NII fraction and tilt shift followed by the freed properties.
+    let with_params = |p: &[f64]| {
+        let mut values = nominal;
+        let mut extra = p[2..].iter();
+        for (value, _) in values.iter_mut().zip(&freed).filter(|(_, &f)| f) {
+            *value = *extra.next().unwrap();
+        }
+        TransmissionModel {
+            spec: FilterSpec {
+                cwl: values[0],
+                effective_index: values[1],
+                ..model.spec
+            },
+            laser_fwhm: values[2],
+            ..model.clone()
+        }
+    };
+
+    let datafluxnorm = normalize_flux(dataflux);
+    let weights = fit_weights(dataflux, dataerr);
+    let objective = |p: &[f64]| {
+        let m = with_params(p);
+        if p[0] < 0.
+            || p[0] > 1.
+            || (m.spec.cwl - model.spec.cwl).abs() > CWL_RANGE
+            || m.spec.effective_index <= 1.
+            || m.laser_fwhm <= 0.
+        {
+            f64::INFINITY
+        } else {
+            match m.compute() {
+                Ok(curves) => tilt_residual(&curves, datatilt, &datafluxnorm, &weights, p[0], p[1]),
+                Err(_) => f64::INFINITY,
+            }
+        }
+    };
+
+    let x0 = [seed.nii_fraction, seed.tilt_shift]
+        .iter()
+        .chain(
+            nominal
+                .iter()
+                .zip(&freed)
+                .filter(|(_, &f)| f)
+                .map(|(v, _)| v),
+        )
+        .cloned()
+        .collect::<Vec<_>>();
+    let step = [if seed.nii_fraction > 0.5 { -0.05 } else { 0.05 }, 0.5]
+        .iter()
+        .chain(
+            FILTER_STEPS
+                .iter()
+                .zip(&freed)
+                .filter(|(_, &f)| f)
+                .map(|(s, _)| s),
+        )
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let min = nelder_mead(&objective, &x0, &step, 1e-10, 2000);
+    Ok(FilterFit {
+        tilt: TiltFit {
+            nii_fraction: min.x[0],
+            tilt_shift: min.x[1],
+            residual: min.fx,
+        },
+        model: with_params(&min.x),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calibration::simulation::{simulated_sweep, sweep_fluxes, SimulatedRig};
+
+    #[test]
+    fn test_fit_weights() {
+        let flux = [16., 64., 4.];
+        assert_eq!(
+            fit_weights(&flux, None),
+            vec![1.25_f64.sqrt(), 2_f64.sqrt(), 1.0625_f64.sqrt()]
+        );
+        assert_eq!(
+            fit_weights(&flux, Some(&[8., 16., 32.][..])),
+            vec![8., 4., 2.]
+        );
+
+        // An angle without a measured scatter, e.g. from a single frame, takes the median of
+        // the measured uncertainties rather than dropping the weighting altogether.
+        let err = [8., 16., 0.];
+        assert_eq!(fill_errors(&err), Some((vec![8., 16., 12.], 1)));
+        assert_eq!(fit_weights(&flux, Some(&err[..])), vec![8., 4., 64. / 12.]);
+        assert_eq!(
+            fill_errors(&[8., f64::NAN, 16., 32.]),
+            Some((vec![8., 16., 16., 32.], 1))
+        );
+
+        // With none measured, the weighting is as without uncertainties.
+        assert_eq!(fill_errors(&[0., f64::NAN, 0.]), None);
+        assert_eq!(
+            fit_weights(&flux, Some(&[0., f64::NAN, 0.][..])),
+            fit_weights(&flux, None)
+        );
+    }
+
+    #[test]
+    fn test_recovers_injected_parameters() {
+        let (datatilt, dataflux) = simulated_sweep(0.);
+        let fit = fit_tilt_calibration(&TransmissionCurves::default(), &datatilt, &dataflux, None)
+            .unwrap();
+        assert!((fit.tilt_shift - 2.).abs() < 0.05, "{:?}", fit);
+        assert!((fit.nii_fraction - 0.4).abs() < 0.02, "{:?}", fit);
+    }
+
+    #[test]
+    fn test_recovers_filter_cwl() {
+        let nominal = SimulatedRig::default().spec;
+        let rig = SimulatedRig {
+            zeropoint_offset: 2.,
+            nii_fraction: 0.4,
+            spec: FilterSpec {
+                cwl: nominal.cwl + 1.2,
+                ..nominal
+            },
+            ..Default::default()
+        };
+        let (datatilt, dataflux) = sweep_fluxes(&rig, 0.);
+
+        let model = TransmissionModel::builder().model().clone();
+        let free = FreeParameters {
+            filter_cwl: true,
+            ..Default::default()
+        };
+        let fit = fit_filter_calibration(&model, free, &datatilt, &dataflux, None).unwrap();
+        assert!(
+            (fit.model.spec.cwl - rig.spec.cwl).abs() < 0.05,
+            "{:?}",
+            fit
+        );
+        assert!((fit.tilt.tilt_shift - 2.).abs() < 0.1, "{:?}", fit);
+
+        // The nominal model leaves the measured curve at its nominal CWL, and no tilt shift fits
+        // it to the offset passband.
+        let untranslated =
+            fit_tilt_calibration(&model.compute().unwrap(), &datatilt, &dataflux, None).unwrap();
+        assert!(
+            untranslated.residual > 100. * fit.tilt.residual.max(1e-8),
+            "{:?} {:?}",
+            untranslated,
+            fit
+        );
+    }
+
+    #[test]
+    fn test_rejects_data_without_signal() {
+        let curves = TransmissionCurves::default();
+        let datatilt = [-10., 0., 10.];
+        for dataflux in &[[0., 0., 0.], [0., f64::NAN, 1.]] {
+            match fit_tilt_calibration(&curves, &datatilt, dataflux, None) {
+                Err(Error::Fit(_)) => {}
+                other => panic!("{:?}", other),
+            }
+        }
+        assert!(fit_tilt_calibration(&curves, &datatilt, &[0., 1.], None).is_err());
+        assert!(fit_tilt_calibration(&curves, &datatilt, &[1., 2., 1.], Some(&[1.][..])).is_err());
+    }
+}
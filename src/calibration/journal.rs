@@ -0,0 +1,256 @@
+//! On-disk journal of a calibration run. The first line of `<dir>/<run-id>.jsonl` records the
+//! settings of the sweep, and every measured `FrameData` is appended after it as soon as it is
+//! taken, so an interrupted run can be resumed and a finished one refitted without repeating the
+//! exposures.
+
+use std::{
+    fs::{create_dir_all, read_to_string, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use super::data_collection::FrameData;
+use crate::error::{Error, Result};
+
+/// Directory run journals are written to by default.
+pub const DEFAULT_JOURNAL_DIR: &str = "runs";
+
+/// Settings a sweep was started with. A run can only be resumed with the same settings, as its
+/// frames would not belong to the same sweep otherwise.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SweepConfig {
+    pub start: f64,
+    pub end: f64,
+    pub nstep: usize,
+    pub exptime: f64,
+    pub filter: String,
+    pub simulation: bool,
+}
+
+/// First line of a journal.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    config: SweepConfig,
+}
+
+/// An identifier for a new run, from the current UTC time.
+pub fn new_run_id() -> String {
+    Utc::now().format("%Y%m%d-%H%M%S").to_string()
+}
+
+/// Path of the journal for `run_id` in `dir`.
+pub fn journal_path<P: AsRef<Path>>(dir: P, run_id: &str) -> PathBuf {
+    dir.as_ref().join(format!("{}.jsonl", run_id))
+}
+
+/// Read the sweep settings and the frames recorded in a journal, in the order they were taken. A
+/// last line without its newline, as left by a run killed mid-write, is ignored.
+pub fn read_journal<P: AsRef<Path>>(path: P) -> Result<(SweepConfig, Vec<FrameData>)> {
+    let (config, frames, _) = parse_journal(path.as_ref())?;
+    Ok((config, frames))
+}
+
+/// Parse a journal, also returning the length in bytes of its complete lines.
+fn parse_journal(path: &Path) -> Result<(SweepConfig, Vec<FrameData>, u64)> {
+    let contents = read_to_string(path)?;
+    let complete = contents.rfind('\n').map_or(0, |i| i + 1);
+    let error = |i: usize, msg: String| {
+        Error::Journal(format!("{} line {}: {}", path.display(), i + 1, msg))
+    };
+
+    let mut lines = contents[..complete].lines().enumerate();
+    let config = match lines.next() {
+        Some((i, line)) => {
+            serde_json::from_str::<Header>(line)
+                .map_err(|e| error(i, e.to_string()))?
+                .config
+        }
+        None => return Err(error(0, "missing sweep settings".to_owned())),
+    };
+
+    let mut frames = Vec::new();
+    for (i, line) in lines.filter(|(_, line)| !line.trim().is_empty()) {
+        frames.push(serde_json::from_str(line).map_err(|e| error(i, e.to_string()))?);
+    }
+    Ok((config, frames, complete as u64))
+}
+
+/// A run journal open for appending.
+#[derive(Debug)]
+pub struct RunJournal {
+    run_id: String,
+    path: PathBuf,
+    file: File,
+}
+
+impl RunJournal {
+    /// Start a new journal for `run_id` in `dir` with the settings of its sweep, creating the
+    /// directory if needed. Fails if a journal for the run already exists.
+    pub fn create<P: AsRef<Path>>(dir: P, run_id: &str, config: &SweepConfig) -> Result<Self> {
+        create_dir_all(dir.as_ref())?;
+        let path = journal_path(dir, run_id);
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut journal = RunJournal {
+            run_id: run_id.to_owned(),
+            path,
+            file,
+        };
+        journal.write_line(&Header {
+            config: config.clone(),
+        })?;
+        Ok(journal)
+    }
+
+    /// Reopen the journal for `run_id` in `dir`, returning it with the frames already recorded.
+    /// Fails if the run was started with settings other than `config`. A truncated last line is
+    /// cut from the file, leaving the complete ones untouched, so that new frames start on a
+    /// fresh line.
+    pub fn resume<P: AsRef<Path>>(
+        dir: P,
+        run_id: &str,
+        config: &SweepConfig,
+    ) -> Result<(Self, Vec<FrameData>)> {
+        let path = journal_path(dir, run_id);
+        let (recorded, frames, complete) = parse_journal(&path)?;
+        if &recorded != config {
+            return Err(Error::Journal(format!(
+                "run {} was started with {:?}, cannot resume it with {:?}",
+                run_id, recorded, config
+            )));
+        }
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        file.set_len(complete)?;
+        file.sync_data()?;
+
+        Ok((
+            RunJournal {
+                run_id: run_id.to_owned(),
+                path,
+                file,
+            },
+            frames,
+        ))
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a frame and flush it to disk.
+    pub fn append(&mut self, frame: &FrameData) -> Result<()> {
+        self.write_line(frame)
+    }
+
+    fn write_line<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let mut line = serde_json::to_string(value)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    fn frame(angle: f64) -> FrameData {
+        FrameData {
+            angle,
+            raw_angle: angle + 0.01,
+            nobj: 1,
+            spotflux: 1000. + angle,
+            spotarea: 100.,
+            spotflux_err: 10.,
+            spotarea_err: 1.,
+            spots: Vec::new(),
+        }
+    }
+
+    fn config() -> SweepConfig {
+        SweepConfig {
+            start: 160.,
+            end: 200.,
+            nstep: 30,
+            exptime: 60.,
+            filter: "3.1BPF_0deg".to_owned(),
+            simulation: true,
+        }
+    }
+
+    #[test]
+    fn test_resume_after_truncated_write() {
+        let dir = TempDir::new("runs");
+        let mut journal = RunJournal::create(&dir, "run", &config()).unwrap();
+        journal.append(&frame(160.)).unwrap();
+        journal.append(&frame(161.4)).unwrap();
+        assert!(RunJournal::create(&dir, "run", &config()).is_err());
+
+        // A run killed halfway through writing a line.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(journal.path())
+            .unwrap();
+        file.write_all(br#"{"angle":162.8,"raw_an"#).unwrap();
+        drop(journal);
+
+        let changed = SweepConfig {
+            nstep: 40,
+            ..config()
+        };
+        match RunJournal::resume(&dir, "run", &changed) {
+            Err(Error::Journal(msg)) => assert!(msg.contains("nstep: 30"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+
+        let (mut journal, frames) = RunJournal::resume(&dir, "run", &config()).unwrap();
+        assert_eq!(
+            frames.iter().map(|f| f.angle).collect::<Vec<_>>(),
+            vec![160., 161.4]
+        );
+        journal.append(&frame(162.8)).unwrap();
+
+        let (recorded, frames) = read_journal(journal_path(&dir, "run")).unwrap();
+        assert_eq!(recorded, config());
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2].spotflux, frame(162.8).spotflux);
+    }
+
+    #[test]
+    fn test_malformed_line() {
+        let dir = TempDir::new("runs");
+        let path = journal_path(&dir, "bad");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"not json\n{\"angle\":1}\n")
+            .unwrap();
+        match read_journal(&path) {
+            Err(Error::Journal(msg)) => assert!(msg.contains("line 1"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+
+        let mut journal = RunJournal::create(&dir, "frame", &config()).unwrap();
+        journal.append(&frame(160.)).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(journal.path())
+            .unwrap();
+        file.write_all(b"{\"angle\":1}\n").unwrap();
+        match read_journal(journal.path()) {
+            Err(Error::Journal(msg)) => assert!(msg.contains("line 3"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -1,7 +1,41 @@
+pub mod aoi;
+pub mod combine;
+pub mod dark;
 pub mod data_collection;
+#[cfg(unix)]
+pub mod emulator;
+pub mod filter_tilter;
+pub mod fit;
+pub mod journal;
 pub mod model;
+pub mod planner;
+pub mod posterior;
+pub mod registry;
+pub mod scan;
+pub mod simulation;
+pub mod spectrum;
+pub mod spot;
+pub mod sweep;
+pub mod synthetic;
 pub mod transmission;
+pub mod uncertainty;
 
+pub use aoi::*;
+pub use combine::*;
+pub use dark::*;
 pub use data_collection::*;
+pub use filter_tilter::*;
+pub use fit::*;
+pub use journal::*;
 pub use model::*;
+pub use planner::*;
+pub use posterior::*;
+pub use registry::*;
+pub use scan::*;
+pub use simulation::*;
+pub use spectrum::*;
+pub use spot::*;
+pub use sweep::*;
+pub use synthetic::*;
 pub use transmission::*;
+pub use uncertainty::*;
@@ -1,3 +1,23 @@
+use std::{
+    fs::{create_dir_all, rename, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    process,
+};
+
+use compute::prelude::arange;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    aoi::InterpolatedTransmission,
+    spectrum::SourceSpectrum,
+    transmission::{
+        get_tilt_shift, shifted_transmission, Filter, FilterSpec, Wavefront, DEFAULT_FILTER_SPEC,
+    },
+};
+use crate::error::{Error, Result};
+
 /// Outputs from generate_model_transmission, run with a 0.1 degree tilt grid.
 
 pub const MODEL_TILT: [f64; 200] = [
@@ -700,3 +720,355 @@ pub const MODEL_FLUX_NII_COARSE: [f64; 40] = [
     0.000007711983053340545,
     0.000004810654375620572,
 ];
+
+/// Directory model transmission curves are cached in, unless overridden.
+pub const DEFAULT_CACHE_DIR: &str = "cache";
+
+/// Parameters of a model transmission calculation. Two models with the same parameters give
+/// the same curves, so these double as the cache key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransmissionModel {
+    /// Filter in the light path.
+    pub filter: Filter,
+    /// Wavefront the filter is illuminated with.
+    pub wavefront: Wavefront,
+    /// CWL, effective index and bandwidth of the filter.
+    pub spec: FilterSpec,
+    /// Central wavelengths of the laser lines, in nm. The first is the primary line; any others
+    /// are contaminants (e.g. [NII]) whose strength is fitted relative to it.
+    pub laser_lines: Vec<f64>,
+    /// FWHM of each laser line, in nm.
+    pub laser_fwhm: f64,
+    /// Tilts, in degrees, to evaluate the transmission at.
+    pub tilts: Vec<f64>,
+    /// Interpolate between the filter's measurements at two tilts (see
+    /// `InterpolatedTransmission`) instead of shifting its curve rigidly. The bandwidth in
+    /// `spec` is ignored in that case.
+    #[serde(default)]
+    pub interpolate_tilt: bool,
+}
+
+/// Model spot flux through a tilted filter for each laser line, on a grid of tilts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransmissionCurves {
+    /// Tilts, in degrees.
+    pub tilt: Vec<f64>,
+    /// Integrated flux at each tilt, one curve per laser line.
+    pub flux: Vec<Vec<f64>>,
+}
+
+impl Default for TransmissionCurves {
+    /// The precomputed `MODEL_TILT`, `MODEL_FLUX` and `MODEL_FLUX_NII` curves.
+    fn default() -> Self {
+        TransmissionCurves {
+            tilt: MODEL_TILT.to_vec(),
+            flux: vec![MODEL_FLUX.to_vec(), MODEL_FLUX_NII.to_vec()],
+        }
+    }
+}
+
+impl TransmissionCurves {
+    /// Curves from the flux of each line at each tilt, as `SourceSpectrum::integrate_lines`
+    /// gives it.
+    fn from_tilts(tilt: Vec<f64>, flux_at_tilt: &[Vec<f64>]) -> Self {
+        let nlines = flux_at_tilt.first().map_or(0, |f| f.len());
+        TransmissionCurves {
+            tilt,
+            flux: (0..nlines)
+                .map(|line| flux_at_tilt.iter().map(|f| f[line]).collect())
+                .collect(),
+        }
+    }
+
+    /// Total flux at each tilt with the contaminant lines scaled by `contamination` relative to
+    /// the primary line.
+    pub fn total_flux(&self, contamination: f64) -> Vec<f64> {
+        (0..self.tilt.len())
+            .map(|i| {
+                self.flux[0][i] + contamination * self.flux[1..].iter().map(|f| f[i]).sum::<f64>()
+            })
+            .collect()
+    }
+}
+
+impl TransmissionModel {
+    pub fn builder() -> TransmissionModelBuilder {
+        TransmissionModelBuilder::default()
+    }
+
+    /// The laser lines as one source spectrum, each line of unit strength.
+    pub fn source(&self) -> Result<SourceSpectrum> {
+        SourceSpectrum::lasers(&self.laser_lines, self.laser_fwhm)
+    }
+
+    /// Compute the transmission curves, without touching the cache. The laser lines are
+    /// integrated together, as one source spectrum, at each tilt. Fails without any laser lines,
+    /// if they have no width, if the filter isn't registered, or if interpolating between tilts
+    /// for a filter measured at only one.
+    pub fn compute(&self) -> Result<TransmissionCurves> {
+        if self.laser_lines.is_empty() {
+            return Err(Error::Model("at least one laser line is needed".to_owned()));
+        }
+        if self.interpolate_tilt {
+            return self.compute_interpolated();
+        }
+
+        let source = self.source()?;
+        let shifts = get_tilt_shift(&self.spec, &self.tilts);
+
+        let flux = shifts
+            .par_iter()
+            .map(|&shift| {
+                let (wavelength, transmission) =
+                    shifted_transmission(self.filter, &self.spec, Some(shift), self.wavefront)?;
+                Ok(source.integrate_lines(&wavelength, &transmission))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(TransmissionCurves::from_tilts(self.tilts.clone(), &flux))
+    }
+
+    fn compute_interpolated(&self) -> Result<TransmissionCurves> {
+        let aoi = InterpolatedTransmission::for_filter(self.filter)
+            .ok_or_else(|| {
+                Error::Model(format!(
+                    "no measurement of {} at a second tilt to interpolate with",
+                    self.filter
+                ))
+            })?
+            .spec(self.spec);
+        let cone_angle = self.wavefront.cone_angle();
+        let source = self.source()?;
+
+        let flux = self
+            .tilts
+            .par_iter()
+            .map(|&tilt| {
+                let (wavelength, transmission) = aoi.transmission(tilt, cone_angle)?;
+                Ok(source.integrate_lines(&wavelength, &transmission))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(TransmissionCurves::from_tilts(self.tilts.clone(), &flux))
+    }
+
+    /// A stable key identifying these parameters (FNV-1a of their JSON representation).
+    pub fn cache_key(&self) -> String {
+        let json = serde_json::to_string(self).expect("Could not serialize model parameters.");
+        let hash = json.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{:016x}", hash)
+    }
+
+    /// Load the curves for these parameters from the cache in `cache_dir`, computing and
+    /// caching them if they aren't there yet. A cache file that can't be read or parsed is
+    /// treated as missing and overwritten.
+    pub fn load_or_compute(&self, cache_dir: &Path) -> Result<TransmissionCurves> {
+        let path = cache_dir.join(format!("transmission-{}.json", self.cache_key()));
+
+        let cached = File::open(&path)
+            .ok()
+            .and_then(|f| serde_json::from_reader::<_, CachedTransmission>(BufReader::new(f)).ok());
+        if let Some(cached) = cached {
+            // Guard against hash collisions.
+            if serde_json::to_string(&cached.model)? == serde_json::to_string(self)? {
+                return Ok(cached.curves);
+            }
+        }
+
+        let cached = CachedTransmission {
+            model: self.clone(),
+            curves: self.compute()?,
+        };
+        create_dir_all(cache_dir)?;
+        // Write next to the cache file and rename over it, so that an interrupted write never
+        // leaves a truncated cache file behind.
+        let tmp = path.with_extension(format!("json.{}.tmp", process::id()));
+        serde_json::to_writer(File::create(&tmp)?, &cached)?;
+        rename(&tmp, &path)?;
+
+        Ok(cached.curves)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedTransmission {
+    model: TransmissionModel,
+    curves: TransmissionCurves,
+}
+
+/// Builder for `TransmissionModel`. Defaults match the precomputed `MODEL_*` curves: the
+/// 3.1 nm filter at 659.9 nm in a collimated beam, Hα and [NII] laser lines 0.61 nm wide, and
+/// tilts from 0 to 20 degrees in steps of 0.1.
+#[derive(Debug, Clone)]
+pub struct TransmissionModelBuilder {
+    model: TransmissionModel,
+    cache_dir: Option<PathBuf>,
+}
+
+impl Default for TransmissionModelBuilder {
+    fn default() -> Self {
+        TransmissionModelBuilder {
+            model: TransmissionModel {
+                filter: Filter::Bpf31Deg0,
+                wavefront: Wavefront::TCOLL,
+                spec: DEFAULT_FILTER_SPEC,
+                laser_lines: vec![656.3, 658.5],
+                laser_fwhm: 0.61,
+                tilts: arange(0., 20., 0.1).to_vec(),
+                interpolate_tilt: false,
+            },
+            cache_dir: Some(PathBuf::from(DEFAULT_CACHE_DIR)),
+        }
+    }
+}
+
+impl TransmissionModelBuilder {
+    /// Use `filter`, taking its nominal bandwidth if it is registered. Curves can't be computed
+    /// for a filter that isn't.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.model.filter = filter;
+        if let Ok(bandwidth) = filter.bandwidth() {
+            self.model.spec.bandwidth = bandwidth;
+        }
+        self
+    }
+
+    pub fn wavefront(mut self, wavefront: Wavefront) -> Self {
+        self.model.wavefront = wavefront;
+        self
+    }
+
+    pub fn spec(mut self, spec: FilterSpec) -> Self {
+        self.model.spec = spec;
+        self
+    }
+
+    pub fn filter_cwl(mut self, cwl: f64) -> Self {
+        self.model.spec.cwl = cwl;
+        self
+    }
+
+    pub fn effective_index(mut self, effective_index: f64) -> Self {
+        self.model.spec.effective_index = effective_index;
+        self
+    }
+
+    pub fn bandwidth(mut self, bandwidth: f64) -> Self {
+        self.model.spec.bandwidth = bandwidth;
+        self
+    }
+
+    pub fn laser_lines(mut self, laser_lines: &[f64]) -> Self {
+        self.model.laser_lines = laser_lines.to_vec();
+        self
+    }
+
+    pub fn laser_fwhm(mut self, laser_fwhm: f64) -> Self {
+        self.model.laser_fwhm = laser_fwhm;
+        self
+    }
+
+    /// Interpolate between the filter's measurements at two tilts instead of shifting one.
+    pub fn interpolate_tilt(mut self, interpolate_tilt: bool) -> Self {
+        self.model.interpolate_tilt = interpolate_tilt;
+        self
+    }
+
+    /// Evaluate at tilts from `start` up to (but excluding) `end` in steps of `step` degrees.
+    pub fn tilt_grid(mut self, start: f64, end: f64, step: f64) -> Self {
+        self.model.tilts = arange(start, end, step).to_vec();
+        self
+    }
+
+    /// Cache curves in `cache_dir`, or don't cache them at all if `None`.
+    pub fn cache_dir<P: AsRef<Path>>(mut self, cache_dir: Option<P>) -> Self {
+        self.cache_dir = cache_dir.map(|p| p.as_ref().to_path_buf());
+        self
+    }
+
+    /// The model parameters as configured.
+    pub fn model(&self) -> &TransmissionModel {
+        &self.model
+    }
+
+    /// Compute the curves, or load them from the cache.
+    pub fn build(self) -> Result<TransmissionCurves> {
+        match &self.cache_dir {
+            Some(dir) => self.model.load_or_compute(dir),
+            None => self.model.compute(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn test_default_builder_matches_constants() {
+        let curves = TransmissionModel::builder()
+            .tilt_grid(0., 20., 0.5)
+            .cache_dir(None::<&str>)
+            .build()
+            .unwrap();
+        let coarse = TransmissionCurves {
+            tilt: MODEL_TILT_COARSE.to_vec(),
+            flux: vec![MODEL_FLUX_COARSE.to_vec(), MODEL_FLUX_NII_COARSE.to_vec()],
+        };
+
+        // The constants were integrated on the old fixed laser grid, which agrees with the
+        // source spectrum integration to about 1e-4.
+        assert_eq!(curves.tilt.len(), coarse.tilt.len());
+        for i in 0..curves.tilt.len() {
+            assert!((curves.tilt[i] - coarse.tilt[i]).abs() < 1e-9);
+            for line in 0..2 {
+                let expected = coarse.flux[line][i];
+                assert!((curves.flux[line][i] - expected).abs() <= 1e-4 * expected.abs() + 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        let dir = TempDir::new("cache");
+        let builder = TransmissionModel::builder()
+            .laser_lines(&[656.3])
+            .tilt_grid(0., 5., 1.)
+            .cache_dir(Some(&dir));
+        let key = builder.model().cache_key();
+
+        let path = dir.path().join(format!("transmission-{}.json", key));
+        let computed = builder.clone().build().unwrap();
+        assert!(path.exists());
+        let cached = builder.clone().build().unwrap();
+        assert_eq!(computed.flux, cached.flux);
+
+        // A write cut short is recomputed rather than an error.
+        std::fs::write(&path, "{\"model\": {\"filt").unwrap();
+        assert_eq!(builder.build().unwrap().flux, computed.flux);
+        assert!(
+            serde_json::from_reader::<_, CachedTransmission>(File::open(&path).unwrap()).is_ok()
+        );
+
+        let other = TransmissionModel::builder()
+            .laser_fwhm(0.5)
+            .model()
+            .cache_key();
+        assert_ne!(key, other);
+    }
+
+    #[test]
+    fn test_invalid_models() {
+        let builder = TransmissionModel::builder()
+            .tilt_grid(0., 5., 1.)
+            .cache_dir(None::<&str>);
+        assert!(matches!(
+            builder.clone().laser_lines(&[]).build(),
+            Err(Error::Model(_))
+        ));
+        assert!(builder.model().compute().is_ok());
+    }
+}
@@ -0,0 +1,265 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    filter_tilter::TravelLimits,
+    transmission::{
+        get_tilt_shift, integrate_flux, tilt_for_wavelength, Filter, FilterSpec, Wavefront,
+    },
+};
+use crate::error::{Error, Result};
+
+/// Speed of light, in km/s.
+pub const SPEED_OF_LIGHT: f64 = 299_792.458;
+/// Default FWHM of an emission line, in nm, when its intrinsic width is unknown.
+pub const DEFAULT_LINE_FWHM: f64 = 0.3;
+
+/// Nebular emission lines the narrowband filters are pointed at.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EmissionLine {
+    HAlpha,
+    NII6548,
+    NII6583,
+    SII6716,
+    SII6731,
+    OIII4959,
+    OIII5007,
+}
+
+/// Every line in the catalogue, used to look for contaminants.
+pub const EMISSION_LINES: [EmissionLine; 7] = [
+    EmissionLine::HAlpha,
+    EmissionLine::NII6548,
+    EmissionLine::NII6583,
+    EmissionLine::SII6716,
+    EmissionLine::SII6731,
+    EmissionLine::OIII4959,
+    EmissionLine::OIII5007,
+];
+
+impl EmissionLine {
+    /// Rest wavelength in air, in nm.
+    pub fn rest_wavelength(&self) -> f64 {
+        match self {
+            EmissionLine::HAlpha => 656.28,
+            EmissionLine::NII6548 => 654.80,
+            EmissionLine::NII6583 => 658.34,
+            EmissionLine::SII6716 => 671.65,
+            EmissionLine::SII6731 => 673.08,
+            EmissionLine::OIII4959 => 495.89,
+            EmissionLine::OIII5007 => 500.68,
+        }
+    }
+}
+
+impl FromStr for EmissionLine {
+    type Err = String;
+
+    /// Parse a line name such as `halpha`, `nii6583` or `oiii5007`. The bare ion names `nii`,
+    /// `sii` and `oiii` refer to the stronger line of each doublet.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "halpha" | "ha" => Ok(EmissionLine::HAlpha),
+            "nii6548" => Ok(EmissionLine::NII6548),
+            "nii6583" | "nii" => Ok(EmissionLine::NII6583),
+            "sii6716" | "sii" => Ok(EmissionLine::SII6716),
+            "sii6731" => Ok(EmissionLine::SII6731),
+            "oiii4959" => Ok(EmissionLine::OIII4959),
+            "oiii5007" | "oiii" => Ok(EmissionLine::OIII5007),
+            _ => Err(format!("unknown emission line {:?}", s)),
+        }
+    }
+}
+
+/// Redshift corresponding to a recession velocity in km/s (optical convention, `cz`).
+pub fn velocity_to_redshift(velocity: f64) -> f64 {
+    velocity / SPEED_OF_LIGHT
+}
+
+/// Transmission of a neighbouring line when the filter is centred on the target.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Contaminant {
+    pub line: EmissionLine,
+    /// Observed wavelength, in nm.
+    pub wavelength: f64,
+    /// Transmitted flux relative to the target line, for equal intrinsic line fluxes.
+    pub relative_flux: f64,
+}
+
+/// How to tilt the filter to observe a line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinePlan {
+    /// Rest wavelength of the target line, in nm.
+    pub rest_wavelength: f64,
+    pub redshift: f64,
+    /// Observed wavelength of the target line, in nm.
+    pub wavelength: f64,
+    /// Physical tilt of the filter, in degrees.
+    pub tilt: f64,
+    /// Raw tilter angle to command.
+    pub raw_angle: f64,
+    /// Fraction of the line flux transmitted at that tilt.
+    pub peak_transmission: f64,
+    /// Neighbouring lines that leak through the filter.
+    pub contaminants: Vec<Contaminant>,
+    /// Fraction of the transmitted flux coming from neighbouring lines, for equal intrinsic
+    /// line fluxes.
+    pub contamination: f64,
+}
+
+/// Works out the tilter angle that centres a filter on a (redshifted) emission line, using a
+/// calibrated zero point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinePlanner {
+    pub filter: Filter,
+    pub spec: FilterSpec,
+    pub wavefront: Wavefront,
+    /// Tilt of the filter, in degrees, at a raw angle of 180 (the calibrated tilt shift).
+    pub tilt_shift: f64,
+    /// FWHM of the emission lines, in nm.
+    pub line_fwhm: f64,
+    pub limits: TravelLimits,
+}
+
+impl LinePlanner {
+    /// Plan with the nominal specification of `filter`. Fails if the filter isn't registered.
+    pub fn new(filter: Filter, tilt_shift: f64) -> Result<Self> {
+        Ok(LinePlanner {
+            filter,
+            spec: FilterSpec::nominal(filter)?,
+            wavefront: Wavefront::TCOLL,
+            tilt_shift,
+            line_fwhm: DEFAULT_LINE_FWHM,
+            limits: TravelLimits::default(),
+        })
+    }
+
+    pub fn spec(mut self, spec: FilterSpec) -> Self {
+        self.spec = spec;
+        self
+    }
+
+    pub fn wavefront(mut self, wavefront: Wavefront) -> Self {
+        self.wavefront = wavefront;
+        self
+    }
+
+    pub fn line_fwhm(mut self, line_fwhm: f64) -> Self {
+        self.line_fwhm = line_fwhm;
+        self
+    }
+
+    pub fn limits(mut self, limits: TravelLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Raw angle that puts the filter at a physical tilt. The transmission is symmetric in
+    /// tilt, so the mirrored angle is used if the direct one is outside the travel limits.
+    fn raw_angle_for(&self, tilt: f64) -> Option<f64> {
+        [tilt, -tilt]
+            .iter()
+            .map(|t| 180. + self.tilt_shift + t)
+            .find(|&raw| self.limits.contains(raw))
+    }
+
+    /// Flux transmitted from a line at `wavelength` when the filter is tilted by `tilt`.
+    fn transmitted(&self, tilt: f64, wavelength: f64) -> Result<f64> {
+        let shift = get_tilt_shift(&self.spec, &[tilt])[0];
+        integrate_flux(
+            self.filter,
+            &self.spec,
+            Some(shift),
+            self.wavefront,
+            wavelength,
+            self.line_fwhm,
+        )
+    }
+
+    /// Plan an observation of the line at `rest_wavelength` (nm) and `redshift`.
+    pub fn plan(&self, rest_wavelength: f64, redshift: f64) -> Result<LinePlan> {
+        let wavelength = rest_wavelength * (1. + redshift);
+        let tilt = tilt_for_wavelength(&self.spec, wavelength).ok_or_else(|| {
+            Error::Unreachable(format!(
+                "{:.2} nm is not blueward of the filter CWL {:.2} nm within 90 degrees of tilt",
+                wavelength, self.spec.cwl
+            ))
+        })?;
+        let raw_angle = self.raw_angle_for(tilt).ok_or_else(|| {
+            Error::Unreachable(format!(
+                "a tilt of {:.2} degrees is outside the travel limits [{}, {}]",
+                tilt, self.limits.min, self.limits.max
+            ))
+        })?;
+
+        let peak_transmission = self.transmitted(tilt, wavelength)?;
+        if peak_transmission <= 0. {
+            return Err(Error::Unreachable(format!(
+                "no transmission model for {:.2} nm through {}",
+                wavelength, self.filter
+            )));
+        }
+        let contaminants = EMISSION_LINES
+            .iter()
+            .filter(|line| (line.rest_wavelength() - rest_wavelength).abs() > 1e-3)
+            .map(|&line| {
+                let wavelength = line.rest_wavelength() * (1. + redshift);
+                Ok(Contaminant {
+                    line,
+                    wavelength,
+                    relative_flux: self.transmitted(tilt, wavelength)? / peak_transmission,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|c| c.relative_flux > 0.)
+            .collect::<Vec<_>>();
+        let leaked = contaminants.iter().map(|c| c.relative_flux).sum::<f64>();
+
+        Ok(LinePlan {
+            rest_wavelength,
+            redshift,
+            wavelength,
+            tilt,
+            raw_angle,
+            peak_transmission,
+            contaminants,
+            contamination: leaked / (1. + leaked),
+        })
+    }
+
+    /// Plan an observation of a catalogued line.
+    pub fn plan_line(&self, line: EmissionLine, redshift: f64) -> Result<LinePlan> {
+        self.plan(line.rest_wavelength(), redshift)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plan_halpha() {
+        let planner = LinePlanner::new(Filter::Bpf31Deg0, 1.5).unwrap();
+        let plan = planner
+            .plan_line(EmissionLine::HAlpha, velocity_to_redshift(500.))
+            .unwrap();
+
+        assert!(plan.wavelength > EmissionLine::HAlpha.rest_wavelength());
+        assert!((plan.raw_angle - (180. + 1.5 + plan.tilt)).abs() < 1e-9);
+        assert!(plan.peak_transmission > 0.5);
+        assert!(plan.contamination >= 0. && plan.contamination < 1.);
+        assert!(plan
+            .contaminants
+            .iter()
+            .all(|c| c.line != EmissionLine::HAlpha));
+    }
+
+    #[test]
+    fn test_unreachable_lines() {
+        let planner = LinePlanner::new(Filter::Bpf31Deg0, 0.).unwrap();
+        assert!(planner.plan_line(EmissionLine::SII6716, 0.).is_err());
+        assert!(planner.plan_line(EmissionLine::OIII5007, 0.).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{collections::BTreeMap, fs::File};
+
+use compute::prelude::{Continuous, Normal};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    fit::{fill_errors, normalize_flux, tilt_model, TiltFit},
+    model::TransmissionCurves,
+};
+use crate::{
+    error::{Error, Result},
+    sampling::{Chain, EnsembleSampler, ParameterSummary},
+    utils::randn,
+};
+
+/// Names of the tilt calibration model parameters, in the order they are sampled.
+pub const TILT_PARAMETERS: [&str; 4] = ["tilt_offset", "nii_fraction", "normalization", "noise"];
+
+/// How many random starting positions to try per walker before giving up.
+const MAX_START_ATTEMPTS: usize = 1000;
+
+/// Prior on a single model parameter.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Prior {
+    Uniform {
+        low: f64,
+        high: f64,
+    },
+    Normal {
+        mean: f64,
+        std: f64,
+    },
+    /// Uniform in the logarithm of the parameter, for scale parameters.
+    LogUniform {
+        low: f64,
+        high: f64,
+    },
+}
+
+impl Prior {
+    /// Log prior density at `x` (up to a constant for `Normal`).
+    pub fn ln_pdf(&self, x: f64) -> f64 {
+        match *self {
+            Prior::Uniform { low, high } if x >= low && x <= high => -(high - low).ln(),
+            Prior::Normal { mean, std } => Normal::new(mean, std).pdf(x).ln(),
+            Prior::LogUniform { low, high } if x >= low && x <= high => {
+                -x.ln() - (high / low).ln().ln()
+            }
+            _ => f64::NEG_INFINITY,
+        }
+    }
+}
+
+/// Priors on the tilt calibration model parameters. These differ from unit to unit, so they can
+/// be loaded from a JSON file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TiltPriors {
+    /// Tilt, in degrees, of the filter at a raw angle of 180.
+    pub tilt_offset: Prior,
+    /// Strength of the [NII] line relative to Hα.
+    pub nii_fraction: Prior,
+    /// Peak of the model relative to the brightest measured flux.
+    pub normalization: Prior,
+    /// Standard deviation of the measured fluxes beyond their uncertainties, if known, relative
+    /// to the brightest measured flux.
+    pub noise: Prior,
+}
+
+impl Default for TiltPriors {
+    fn default() -> Self {
+        TiltPriors {
+            tilt_offset: Prior::Uniform {
+                low: -25.,
+                high: 25.,
+            },
+            nii_fraction: Prior::Uniform { low: 0., high: 1. },
+            normalization: Prior::Uniform { low: 0.5, high: 2. },
+            noise: Prior::LogUniform {
+                low: 1e-4,
+                high: 1.,
+            },
+        }
+    }
+}
+
+impl TiltPriors {
+    /// Load priors from a JSON file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+}
+
+/// Posterior of the tilt calibration model given spot fluxes measured at some tilts. Fluxes
+/// are normalized to a peak of 1 and modelled as `normalization * model(tilt - tilt_offset)`
+/// with Gaussian noise of standard deviation `noise`, added in quadrature to the flux
+/// uncertainties if they are given. Uncertainties that were not measured are filled in as for
+/// the fit, see `fill_errors`.
+pub struct TiltPosterior {
+    curves: TransmissionCurves,
+    datatilt: Vec<f64>,
+    datafluxnorm: Vec<f64>,
+    dataerrnorm: Option<Vec<f64>>,
+    priors: TiltPriors,
+}
+
+impl TiltPosterior {
+    pub fn new(
+        curves: &TransmissionCurves,
+        datatilt: &[f64],
+        dataflux: &[f64],
+        dataerr: Option<&[f64]>,
+        priors: TiltPriors,
+    ) -> Self {
+        let max = dataflux.iter().cloned().fold(f64::MIN, f64::max);
+        TiltPosterior {
+            curves: curves.clone(),
+            datatilt: datatilt.to_vec(),
+            datafluxnorm: normalize_flux(dataflux),
+            dataerrnorm: dataerr
+                .and_then(fill_errors)
+                .map(|(err, _)| err.iter().map(|e| e / max).collect()),
+            priors,
+        }
+    }
+
+    /// Log posterior density (up to a constant) at parameters ordered as `TILT_PARAMETERS`.
+    pub fn log_prob(&self, p: &[f64]) -> f64 {
+        let (tilt_offset, nii_fraction, normalization, noise) = (p[0], p[1], p[2], p[3]);
+
+        let ln_prior = self.priors.tilt_offset.ln_pdf(tilt_offset)
+            + self.priors.nii_fraction.ln_pdf(nii_fraction)
+            + self.priors.normalization.ln_pdf(normalization)
+            + self.priors.noise.ln_pdf(noise);
+        if !ln_prior.is_finite() || noise <= 0. {
+            return f64::NEG_INFINITY;
+        }
+
+        let shifted = self
+            .datatilt
+            .iter()
+            .map(|t| t - tilt_offset)
+            .collect::<Vec<_>>();
+        let ln_like = tilt_model(&self.curves, nii_fraction, &shifted)
+            .iter()
+            .zip(&self.datafluxnorm)
+            .enumerate()
+            .map(|(i, (m, d))| {
+                let sigma = match &self.dataerrnorm {
+                    Some(err) => err[i].hypot(noise),
+                    None => noise,
+                };
+                let r = (d - normalization * m) / sigma;
+                -0.5 * r * r - sigma.ln()
+            })
+            .sum::<f64>();
+
+        ln_prior + ln_like
+    }
+
+    /// Sample the posterior with `nwalkers` walkers for `nsteps`, starting from a small ball
+    /// around a point estimate. Fails if the priors exclude the neighbourhood of the point
+    /// estimate, so that no walker can be started there.
+    pub fn sample(&self, start: &TiltFit, nwalkers: usize, nsteps: usize) -> Result<Chain> {
+        // The fit residual is weighted, so start the noise at the plain RMS residual instead.
+        let shifted = self
+            .datatilt
+            .iter()
+            .map(|t| t - start.tilt_shift)
+            .collect::<Vec<_>>();
+        let sumsq = tilt_model(&self.curves, start.nii_fraction, &shifted)
+            .iter()
+            .zip(&self.datafluxnorm)
+            .map(|(m, d)| (d - m) * (d - m))
+            .sum::<f64>();
+        let noise = (sumsq / self.datatilt.len() as f64).sqrt().max(1e-3);
+        let centre = [start.tilt_shift, start.nii_fraction, 1., noise];
+        let scatter = [0.05, 0.01, 0.01, 0.1 * noise];
+
+        // Start every walker somewhere the posterior is finite.
+        let initial = (0..nwalkers)
+            .map(|_| {
+                (0..MAX_START_ATTEMPTS)
+                    .map(|_| {
+                        centre
+                            .iter()
+                            .zip(&scatter)
+                            .map(|(c, s)| c + s * randn())
+                            .collect::<Vec<_>>()
+                    })
+                    .find(|p| self.log_prob(p).is_finite())
+                    .ok_or_else(|| {
+                        Error::Fit(format!(
+                            "could not start the MCMC walkers near the best fit (tilt shift {}, \
+                             NII fraction {}); check the priors",
+                            start.tilt_shift, start.nii_fraction
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(EnsembleSampler::default().run(|p| self.log_prob(p), &initial, nsteps))
+    }
+}
+
+/// One row of a chain written to CSV.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ChainRow {
+    step: usize,
+    walker: usize,
+    tilt_offset: f64,
+    nii_fraction: f64,
+    normalization: f64,
+    noise: f64,
+    log_prob: f64,
+}
+
+/// Write every sample of a tilt calibration chain to a CSV file.
+pub fn write_chain_csv(chain: &Chain, path: &str) -> Result<()> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    for (step, (walkers, lps)) in chain.samples.iter().zip(&chain.log_prob).enumerate() {
+        for (walker, (p, &log_prob)) in walkers.iter().zip(lps).enumerate() {
+            wtr.serialize(ChainRow {
+                step,
+                walker,
+                tilt_offset: p[0],
+                nii_fraction: p[1],
+                normalization: p[2],
+                noise: p[3],
+                log_prob,
+            })?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Posterior summary of a tilt calibration chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainSummary {
+    pub nwalkers: usize,
+    pub nsteps: usize,
+    pub burn: usize,
+    pub thin: usize,
+    pub mean_acceptance_fraction: f64,
+    pub parameters: BTreeMap<String, ParameterSummary>,
+}
+
+impl ChainSummary {
+    pub fn new(chain: &Chain, burn: usize, thin: usize) -> Self {
+        ChainSummary {
+            nwalkers: chain.acceptance_fraction.len(),
+            nsteps: chain.samples.len(),
+            burn,
+            thin,
+            mean_acceptance_fraction: chain.mean_acceptance_fraction(),
+            parameters: TILT_PARAMETERS
+                .iter()
+                .map(|name| name.to_string())
+                .zip(chain.summary(burn, thin))
+                .collect(),
+        }
+    }
+
+    /// Write the summary to a JSON file.
+    pub fn write_json(&self, path: &str) -> Result<()> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calibration::{fit::fit_tilt_calibration, simulation::simulated_sweep};
+
+    #[test]
+    fn test_sampler_recovers_injected_parameters() {
+        let (datatilt, dataflux) = simulated_sweep(0.01);
+        let curves = TransmissionCurves::default();
+        let fit = fit_tilt_calibration(&curves, &datatilt, &dataflux, None).unwrap();
+
+        let posterior =
+            TiltPosterior::new(&curves, &datatilt, &dataflux, None, TiltPriors::default());
+        let chain = posterior.sample(&fit, 16, 400).unwrap();
+        let summary = ChainSummary::new(&chain, 200, 1);
+
+        let tilt_offset = &summary.parameters["tilt_offset"];
+        assert!((tilt_offset.median - 2.).abs() < 0.1, "{:?}", tilt_offset);
+        let nii_fraction = &summary.parameters["nii_fraction"];
+        assert!(
+            (nii_fraction.median - 0.4).abs() < 0.05,
+            "{:?}",
+            nii_fraction
+        );
+        assert!(summary.mean_acceptance_fraction > 0.);
+    }
+
+    #[test]
+    fn test_flux_uncertainties() {
+        let (datatilt, dataflux) = simulated_sweep(0.01);
+        let curves = TransmissionCurves::default();
+        let max = dataflux.iter().cloned().fold(f64::MIN, f64::max);
+        let dataerr = vec![0.01 * max; dataflux.len()];
+        let priors = TiltPriors::default();
+
+        // The noise parameter adds in quadrature to the uncertainties.
+        let with_err = TiltPosterior::new(
+            &curves,
+            &datatilt,
+            &dataflux,
+            Some(dataerr.as_slice()),
+            priors,
+        );
+        let without = TiltPosterior::new(&curves, &datatilt, &dataflux, None, priors);
+        let noise = 0.02;
+        let total = 0.01_f64.hypot(noise);
+        let lp = with_err.log_prob(&[2., 0.4, 1., noise]) - priors.noise.ln_pdf(noise);
+        let expected = without.log_prob(&[2., 0.4, 1., total]) - priors.noise.ln_pdf(total);
+        assert!(
+            (lp - expected).abs() < 1e-9 * expected.abs(),
+            "{} {}",
+            lp,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_priors_excluding_best_fit() {
+        let (datatilt, dataflux) = simulated_sweep(0.01);
+        let curves = TransmissionCurves::default();
+        let fit = fit_tilt_calibration(&curves, &datatilt, &dataflux, None).unwrap();
+
+        let priors = TiltPriors {
+            tilt_offset: Prior::Uniform {
+                low: 10.,
+                high: 12.,
+            },
+            ..Default::default()
+        };
+        match TiltPosterior::new(&curves, &datatilt, &dataflux, None, priors).sample(&fit, 16, 10) {
+            Err(Error::Fit(msg)) => assert!(msg.contains("priors"), "{}", msg),
+            other => panic!("{:?}", other.map(|c| c.samples.len())),
+        }
+    }
+
+    #[test]
+    fn test_priors() {
+        let uniform = Prior::Uniform { low: 0., high: 2. };
+        assert_eq!(uniform.ln_pdf(1.), -(2f64.ln()));
+        assert_eq!(uniform.ln_pdf(3.), f64::NEG_INFINITY);
+
+        let loguniform = Prior::LogUniform {
+            low: 1.,
+            high: std::f64::consts::E,
+        };
+        assert!((loguniform.ln_pdf(2.) + 2f64.ln()).abs() < 1e-12);
+        assert_eq!(loguniform.ln_pdf(0.5), f64::NEG_INFINITY);
+
+        let normal = Prior::Normal { mean: 0., std: 1. };
+        assert!(normal.ln_pdf(0.) > normal.ln_pdf(1.));
+    }
+}
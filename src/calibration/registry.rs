@@ -0,0 +1,265 @@
+//! Filter transmission curves discovered at runtime. Every `<name>.csv` in the filter directory
+//! with a `<name>.json` metadata sidecar is registered under the name given in the sidecar.
+//! CSVs without a sidecar are ignored, and filters that fail to load are skipped and reported.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{read_dir, File},
+    path::{Path, PathBuf},
+};
+
+use compute::prelude::Vector;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use super::transmission::{load_transmission_data, AOIRecord, TransmissionTable, Wavefront};
+use crate::error::Result;
+
+/// Environment variable naming the directory filters are loaded from.
+pub const FILTER_DIR_ENV: &str = "DRAGONFLY_FILTER_DIR";
+/// Directory filters are loaded from if `FILTER_DIR_ENV` is not set.
+pub const DEFAULT_FILTER_DIR: &str =
+    concat!(env!("CARGO_MANIFEST_DIR"), "/data/FilterTransmissionCurves");
+/// Largest relative difference between the bandwidths of two measurements of the same filter.
+/// Bandwidths measured from the curves differ with the tilt and the wavelength sampling.
+pub const COMPANION_BANDWIDTH_TOLERANCE: f64 = 0.1;
+
+/// Contents of a filter's metadata sidecar.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterMetadata {
+    /// Name the filter is registered under.
+    pub name: String,
+    /// Central wavelength at normal incidence, in nm.
+    pub cwl: f64,
+    /// Tilt of the filter, in degrees, when its transmission was measured.
+    pub tilt: f64,
+    /// Bandwidth (FWHM) in nm. Measured from the collimated curve if not given.
+    #[serde(default)]
+    pub bandwidth: Option<f64>,
+}
+
+/// A filter's metadata and measured transmission curve.
+#[derive(Debug, Clone)]
+pub struct RegisteredFilter {
+    pub metadata: FilterMetadata,
+    /// The curve as read from the CSV.
+    pub records: Vec<AOIRecord>,
+    /// The curve resampled so that every wavefront shares one wavelength grid.
+    pub table: TransmissionTable,
+    /// CSV the curve was read from.
+    pub path: PathBuf,
+}
+
+impl RegisteredFilter {
+    /// Read a filter from its CSV and metadata sidecar.
+    pub fn load<P: AsRef<Path>>(csv: P, sidecar: P) -> Result<Self> {
+        let metadata: FilterMetadata = serde_json::from_reader(File::open(sidecar.as_ref())?)?;
+        let records = load_transmission_data(csv.as_ref())?;
+        Ok(RegisteredFilter {
+            metadata,
+            table: TransmissionTable::resample(&records)?,
+            records,
+            path: csv.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Wavelengths and transmission for the given wavefront, on the common grid.
+    pub fn transmission(&self, wavefront: Wavefront) -> (Vector, Vector) {
+        (
+            Vector::from(self.table.wavelength.as_slice()),
+            Vector::from(self.table.column(wavefront)),
+        )
+    }
+
+    /// Bandwidth from the sidecar, or else the full width at half maximum of the collimated
+    /// curve.
+    pub fn bandwidth(&self) -> f64 {
+        self.metadata.bandwidth.unwrap_or_else(|| {
+            let max = self.table.t_coll.iter().cloned().fold(0., f64::max);
+            let (lo, hi) = self
+                .table
+                .wavelength
+                .iter()
+                .zip(&self.table.t_coll)
+                .filter(|(_, &t)| t >= max / 2.)
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), (&w, _)| {
+                    (lo.min(w), hi.max(w))
+                });
+            hi - lo
+        })
+    }
+}
+
+/// Filters available by name.
+#[derive(Debug, Clone, Default)]
+pub struct FilterRegistry {
+    filters: BTreeMap<String, RegisteredFilter>,
+    /// CSVs with a sidecar that could not be loaded, and why.
+    skipped: Vec<(PathBuf, String)>,
+}
+
+impl FilterRegistry {
+    /// Register every CSV in `dir` that has a metadata sidecar. A filter that fails to load is
+    /// skipped and recorded in `skipped` rather than failing the scan; only an unreadable `dir`
+    /// is an error.
+    pub fn scan<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let mut registry = FilterRegistry::default();
+        for entry in read_dir(dir)? {
+            let csv = entry?.path();
+            if csv.extension().map_or(true, |e| e != "csv") {
+                continue;
+            }
+            let sidecar = csv.with_extension("json");
+            if sidecar.exists() {
+                match RegisteredFilter::load(&csv, &sidecar) {
+                    Ok(filter) => registry.insert(filter),
+                    Err(e) => registry.skipped.push((csv, e.to_string())),
+                }
+            }
+        }
+        Ok(registry)
+    }
+
+    /// Scan the directory named by `FILTER_DIR_ENV`, or `DEFAULT_FILTER_DIR` if it isn't set.
+    pub fn from_env() -> Result<Self> {
+        FilterRegistry::scan(filter_dir())
+    }
+
+    /// The registry used to resolve `Filter`s, scanned from `filter_dir()` on first use. Filters
+    /// that failed to load, or the directory itself if it couldn't be read, are left out and
+    /// listed in `skipped`; callers decide whether to report them.
+    pub fn global() -> &'static FilterRegistry {
+        &GLOBAL_REGISTRY
+    }
+
+    /// Register a filter, replacing any with the same name.
+    pub fn insert(&mut self, filter: RegisteredFilter) {
+        self.filters.insert(filter.metadata.name.clone(), filter);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RegisteredFilter> {
+        self.filters.get(name)
+    }
+
+    /// Another measurement of the same filter (same CWL, and bandwidth within
+    /// `COMPANION_BANDWIDTH_TOLERANCE`) at a different tilt, if one is registered.
+    pub fn companion(&self, name: &str) -> Option<&RegisteredFilter> {
+        let filter = self.get(name)?;
+        let bandwidth = filter.bandwidth();
+        self.filters.values().find(|other| {
+            other.metadata.tilt != filter.metadata.tilt
+                && other.metadata.cwl == filter.metadata.cwl
+                && (other.bandwidth() - bandwidth).abs()
+                    <= COMPANION_BANDWIDTH_TOLERANCE * bandwidth
+        })
+    }
+
+    /// Names of all registered filters, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.filters.keys().map(|k| k.as_str())
+    }
+
+    /// Filters found by the scan that failed to load, or for the global registry an unreadable
+    /// filter directory, with the reason.
+    pub fn skipped(&self) -> &[(PathBuf, String)] {
+        &self.skipped
+    }
+}
+
+/// Directory the global registry is scanned from.
+pub fn filter_dir() -> PathBuf {
+    env::var_os(FILTER_DIR_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_FILTER_DIR))
+}
+
+/// The global registry. A directory that can't be scanned leaves it empty, with the directory
+/// recorded as skipped.
+fn load_global() -> FilterRegistry {
+    FilterRegistry::from_env().unwrap_or_else(|e| FilterRegistry {
+        skipped: vec![(filter_dir(), e.to_string())],
+        ..FilterRegistry::default()
+    })
+}
+
+lazy_static! {
+    static ref GLOBAL_REGISTRY: FilterRegistry = load_global();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::io::Write;
+
+    #[test]
+    fn test_scan() {
+        let tmp = TempDir::new("filters");
+        let dir = tmp.path();
+
+        let mut csv = File::create(dir.join("narrow.csv")).unwrap();
+        writeln!(csv, "lambdacoll,Tcoll,lambda22deg,T22deg,lambda3deg,T3deg").unwrap();
+        for (w, t) in &[(655., 0.), (656., 0.8), (657., 1.), (658., 0.8), (659., 0.)] {
+            writeln!(csv, "{},{},{},{},{},{}", w, t, w, t, w, t).unwrap();
+        }
+        File::create(dir.join("narrow.json"))
+            .unwrap()
+            .write_all(br#"{"name": "narrow", "cwl": 657.0, "tilt": 0.0}"#)
+            .unwrap();
+        File::create(dir.join("orphan.csv")).unwrap();
+        File::create(dir.join("broken.csv"))
+            .unwrap()
+            .write_all(b"lambdacoll,Tcoll\n655,0.1\n")
+            .unwrap();
+        File::create(dir.join("broken.json"))
+            .unwrap()
+            .write_all(br#"{"name": "broken", "cwl": 657.0, "tilt": 0.0}"#)
+            .unwrap();
+
+        let registry = FilterRegistry::scan(dir).unwrap();
+        assert_eq!(registry.names().collect::<Vec<_>>(), vec!["narrow"]);
+        assert_eq!(registry.skipped().len(), 1);
+        assert_eq!(registry.skipped()[0].0, dir.join("broken.csv"));
+        let narrow = registry.get("narrow").unwrap();
+        assert_eq!(narrow.metadata.cwl, 657.);
+        assert_eq!(narrow.bandwidth(), 2.);
+        assert_eq!(narrow.transmission(Wavefront::TCOLL).1.len(), 5);
+    }
+
+    #[test]
+    fn test_global_has_builtin_filters() {
+        let registry = FilterRegistry::global();
+        for name in &["0.8BPF_0deg", "0.8BPF_10deg", "3.1BPF_0deg", "3.1BPF_10deg"] {
+            assert!(registry.get(name).is_some(), "{}", name);
+        }
+        let companion = registry.companion("3.1BPF_0deg").unwrap();
+        assert_eq!(companion.metadata.name, "3.1BPF_10deg");
+    }
+
+    #[test]
+    fn test_companion_tolerates_measured_bandwidths() {
+        let filter = |name: &str, tilt: f64, bandwidth: f64| RegisteredFilter {
+            metadata: FilterMetadata {
+                name: name.to_owned(),
+                cwl: 659.9,
+                tilt,
+                bandwidth: Some(bandwidth),
+            },
+            records: Vec::new(),
+            table: TransmissionTable::default(),
+            path: PathBuf::new(),
+        };
+        let mut registry = FilterRegistry::default();
+        registry.insert(filter("normal", 0., 3.1));
+        registry.insert(filter("narrow", 10., 0.8));
+        assert!(registry.companion("normal").is_none());
+
+        // Measured at a tilt, the passband broadens slightly.
+        registry.insert(filter("tilted", 10., 3.15));
+        assert_eq!(
+            registry.companion("normal").unwrap().metadata.name,
+            "tilted"
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Tilt scans of a source through each lens, such as the `data/PNeMeasurements` files, and a
+//! comparison of the tilt shifts fitted to each unit.
+//!
+//! A scan file holds whitespace-separated `tilt flux` pairs, one per line. The first line is a
+//! reference measurement near the peak taken before the scan, and is not part of the scan.
+
+use std::{fs::read_to_string, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    fit::{fit_tilt_calibration, TiltFit},
+    model::TransmissionCurves,
+    uncertainty::{jacobian_uncertainty, TiltFitUncertainty},
+};
+use crate::error::{Error, Result};
+
+/// Spot fluxes measured at a series of tilts through one unit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TiltScan {
+    /// Name of the unit, taken from the trailing digits of the file name (e.g. `301`), or
+    /// the whole file stem if it has none.
+    pub unit: String,
+    /// Tilt, in degrees, of the reference measurement on the first line.
+    pub reference_tilt: f64,
+    /// Flux of the reference measurement on the first line.
+    pub reference_flux: f64,
+    /// Tilts in degrees, relative to a raw angle of 180.
+    pub tilt: Vec<f64>,
+    pub flux: Vec<f64>,
+}
+
+/// Parse a `tilt flux` line, returning `None` if it is not two numbers.
+fn parse_pair(line: &str) -> Option<(f64, f64)> {
+    let mut fields = line.split_whitespace().map(|s| s.parse::<f64>().ok());
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Some(tilt)), Some(Some(flux)), None) if tilt.is_finite() && flux.is_finite() => {
+            Some((tilt, flux))
+        }
+        _ => None,
+    }
+}
+
+impl TiltScan {
+    /// Read a scan from a file. Blank lines are skipped.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let digits = stem
+            .rsplit(|c: char| !c.is_ascii_digit())
+            .next()
+            .unwrap_or("");
+        let unit = if digits.is_empty() {
+            stem.clone()
+        } else {
+            digits.to_owned()
+        };
+
+        let mut pairs = Vec::new();
+        for (i, line) in read_to_string(path)?.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let pair = parse_pair(line).ok_or_else(|| {
+                Error::Scan(format!(
+                    "{} line {}: expected a tilt and a flux, found {:?}",
+                    path.display(),
+                    i + 1,
+                    line
+                ))
+            })?;
+            pairs.push(pair);
+        }
+
+        if pairs.len() < 3 {
+            return Err(Error::Scan(format!(
+                "{}: only {} measurements after the reference line",
+                path.display(),
+                pairs.len().saturating_sub(1)
+            )));
+        }
+        let (reference_tilt, reference_flux) = pairs[0];
+        let (tilt, flux) = pairs[1..].iter().cloned().unzip();
+
+        Ok(TiltScan {
+            unit,
+            reference_tilt,
+            reference_flux,
+            tilt,
+            flux,
+        })
+    }
+
+    /// Fit the NII fraction and tilt shift to the scan, as `bin/calibrate.rs` does for a
+    /// calibration run.
+    pub fn fit(&self, curves: &TransmissionCurves) -> Result<ScanFit> {
+        let fit = fit_tilt_calibration(curves, &self.tilt, &self.flux, None)?;
+        Ok(ScanFit {
+            unit: self.unit.clone(),
+            npoints: self.tilt.len(),
+            uncertainty: jacobian_uncertainty(curves, &self.tilt, &self.flux, None, &fit),
+            fit,
+        })
+    }
+}
+
+/// Best fit to one unit's scan.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanFit {
+    pub unit: String,
+    /// Number of measurements fitted.
+    pub npoints: usize,
+    pub fit: TiltFit,
+    pub uncertainty: TiltFitUncertainty,
+}
+
+/// One unit's tilt shift relative to the others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnitComparison {
+    pub unit: String,
+    pub tilt_shift: f64,
+    pub std_err: f64,
+    /// Tilt shift minus the mean over all units, in degrees.
+    pub deviation: f64,
+    /// `deviation` in units of this unit's standard error.
+    pub significance: f64,
+}
+
+/// Tilt shifts of several units compared with each other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanComparison {
+    pub units: Vec<UnitComparison>,
+    /// Mean tilt shift over all units, in degrees.
+    pub mean_tilt_shift: f64,
+    /// Sample standard deviation of the tilt shifts, in degrees. Zero for a single unit.
+    pub spread: f64,
+    /// Largest minus smallest tilt shift, in degrees.
+    pub range: f64,
+}
+
+impl ScanComparison {
+    pub fn new(fits: &[ScanFit]) -> Self {
+        let n = fits.len() as f64;
+        let shifts = fits.iter().map(|f| f.fit.tilt_shift).collect::<Vec<_>>();
+        let mean = shifts.iter().sum::<f64>() / n;
+        let spread = if fits.len() > 1 {
+            (shifts.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1.)).sqrt()
+        } else {
+            0.
+        };
+        let (min, max) = shifts
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &s| {
+                (lo.min(s), hi.max(s))
+            });
+
+        ScanComparison {
+            units: fits
+                .iter()
+                .map(|f| {
+                    let std_err = f.uncertainty.tilt_shift.std_err;
+                    UnitComparison {
+                        unit: f.unit.clone(),
+                        tilt_shift: f.fit.tilt_shift,
+                        std_err,
+                        deviation: f.fit.tilt_shift - mean,
+                        significance: (f.fit.tilt_shift - mean) / std_err,
+                    }
+                })
+                .collect(),
+            mean_tilt_shift: mean,
+            spread,
+            range: max - min,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::{fs::File, io::Write};
+
+    const PNE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/data/PNeMeasurements");
+
+    #[test]
+    fn test_load_pne_scan() {
+        let scan = TiltScan::from_file(format!("{}/measure_source_301.txt", PNE_DIR)).unwrap();
+        assert_eq!(scan.unit, "301");
+        assert_eq!((scan.reference_tilt, scan.reference_flux), (12., 559436.8));
+        assert_eq!(scan.tilt.len(), 21);
+        assert_eq!((scan.tilt[0], scan.flux[0]), (0.45, 33548.25));
+        assert_eq!((scan.tilt[20], scan.flux[20]), (19.7, 3232.607));
+    }
+
+    #[test]
+    fn test_invalid_scan() {
+        let dir = TempDir::new("scan");
+        let path = dir.path().join("scan.txt");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"12.0 1000.\n1.0 10.\n2.0 abc\n")
+            .unwrap();
+        match TiltScan::from_file(&path) {
+            Err(Error::Scan(msg)) => assert!(msg.contains("line 3"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_fit_and_compare() {
+        let fits = (301..=303)
+            .map(|unit| {
+                TiltScan::from_file(format!("{}/measure_source_{}.txt", PNE_DIR, unit))
+                    .unwrap()
+                    .fit(&TransmissionCurves::default())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!((fits[0].fit.tilt_shift - 0.7).abs() < 0.1, "{:?}", fits[0]);
+        assert!(
+            (fits[0].fit.nii_fraction - 0.1).abs() < 0.03,
+            "{:?}",
+            fits[0]
+        );
+
+        let comparison = ScanComparison::new(&fits);
+        assert_eq!(comparison.units.len(), 3);
+        let total = comparison.units.iter().map(|u| u.deviation).sum::<f64>();
+        assert!(total.abs() < 1e-9);
+        assert!(comparison.range >= comparison.spread);
+    }
+}
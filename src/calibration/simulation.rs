@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    spectrum::{integrate_spectrum, LineProfile, SourceSpectrum},
+    transmission::{get_tilt_shift, Filter, FilterSpec, Wavefront, DEFAULT_FILTER_SPEC},
+};
+use crate::{error::Result, utils::randn};
+
+/// A simulated laser calibration rig: a filter-tilter whose true zero point is offset from
+/// 180 raw degrees by a hidden amount, illuminated by an Hα laser with some [NII] contamination.
+/// Spot fluxes are synthesized from the filter transmission model, so a sweep over it can be
+/// fitted and the fit checked against the injected parameters.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SimulatedRig {
+    /// Filter in the light path.
+    pub filter: Filter,
+    /// Wavefront the filter is illuminated with.
+    pub wavefront: Wavefront,
+    /// CWL, effective index and bandwidth of the filter.
+    pub spec: FilterSpec,
+    /// Central wavelength of the laser, in nm.
+    pub laser_cwl: f64,
+    /// Central wavelength of the [NII] contamination, in nm.
+    pub nii_cwl: f64,
+    /// FWHM of the laser lines, in nm.
+    pub laser_fwhm: f64,
+    /// Tilt of the filter, in degrees, when the tilter reports a raw angle of 180.
+    pub zeropoint_offset: f64,
+    /// Strength of the [NII] line relative to Hα.
+    pub nii_fraction: f64,
+    /// Spot flux, in counts, corresponding to a unit integrated flux.
+    pub flux_scale: f64,
+    /// Standard deviation of the multiplicative noise on each measured flux.
+    pub noise: f64,
+}
+
+impl Default for SimulatedRig {
+    fn default() -> Self {
+        SimulatedRig {
+            filter: Filter::Bpf31Deg0,
+            wavefront: Wavefront::TCOLL,
+            spec: DEFAULT_FILTER_SPEC,
+            laser_cwl: 656.3,
+            nii_cwl: 658.5,
+            laser_fwhm: 0.61,
+            zeropoint_offset: 0.,
+            nii_fraction: 0.,
+            flux_scale: 1e6,
+            noise: 0.,
+        }
+    }
+}
+
+impl SimulatedRig {
+    /// Physical tilt of the filter at a given raw angle.
+    pub fn tilt(&self, raw_angle: f64) -> f64 {
+        raw_angle - 180. - self.zeropoint_offset
+    }
+
+    /// Noiseless spot flux at a given raw angle. Fails if the filter isn't registered or the
+    /// laser lines have no width.
+    pub fn expected_flux(&self, raw_angle: f64) -> Result<f64> {
+        let shift = get_tilt_shift(&self.spec, &[self.tilt(raw_angle)])[0];
+        let source = self.source()?;
+        let flux = integrate_spectrum(
+            self.filter,
+            &self.spec,
+            Some(shift),
+            self.wavefront,
+            &source,
+        )?;
+        Ok(self.flux_scale * flux)
+    }
+
+    /// The Hα laser line plus the [NII] line at `nii_fraction` of its strength.
+    pub fn source(&self) -> Result<SourceSpectrum> {
+        SourceSpectrum::laser(self.laser_cwl, self.laser_fwhm)?.line(
+            self.nii_cwl,
+            self.nii_fraction,
+            LineProfile::Gaussian {
+                fwhm: self.laser_fwhm,
+            },
+        )
+    }
+
+    /// Spot flux at a given raw angle, including noise.
+    pub fn measure_flux(&self, raw_angle: f64) -> Result<f64> {
+        let flux = self.expected_flux(raw_angle)? * (1. + self.noise * randn());
+        Ok(flux.max(0.))
+    }
+}
+
+/// Tilts (raw angle minus 180) and spot fluxes of a sweep of 30 angles from 160 to 200 raw
+/// degrees, for testing the fits. The fluxes carry a fixed pseudo-random multiplicative noise of
+/// relative standard deviation `noise`, so that the tests don't depend on the run.
+#[cfg(test)]
+pub(crate) fn sweep_fluxes(rig: &SimulatedRig, noise: f64) -> (Vec<f64>, Vec<f64>) {
+    let raw_angles = super::sweep::uniform_angles(160., 200., 30);
+    let datatilt = raw_angles.iter().map(|a| a - 180.).collect();
+    let dataflux = raw_angles
+        .iter()
+        .map(|&a| {
+            let u = ((a * 12.9898).sin() * 43758.5453).rem_euclid(1.);
+            rig.expected_flux(a).unwrap() * (1. + noise * 3_f64.sqrt() * (2. * u - 1.))
+        })
+        .collect();
+    (datatilt, dataflux)
+}
+
+/// `sweep_fluxes` of a rig whose zero point is offset by 2 degrees and whose NII fraction is 0.4.
+#[cfg(test)]
+pub(crate) fn simulated_sweep(noise: f64) -> (Vec<f64>, Vec<f64>) {
+    let rig = SimulatedRig {
+        zeropoint_offset: 2.,
+        nii_fraction: 0.4,
+        ..Default::default()
+    };
+    sweep_fluxes(&rig, noise)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calibration::model::{MODEL_FLUX, MODEL_FLUX_NII, MODEL_TILT};
+
+    #[test]
+    fn test_matches_model_transmission() {
+        let rig = SimulatedRig {
+            nii_fraction: 0.5,
+            flux_scale: 1.,
+            ..Default::default()
+        };
+
+        for &i in &[0, 50, 100, 150] {
+            let expected = MODEL_FLUX[i] + 0.5 * MODEL_FLUX_NII[i];
+            let simulated = rig.expected_flux(180. + MODEL_TILT[i]).unwrap();
+            // The constants were integrated on the old fixed laser grid, which agrees with the
+            // source spectrum integration to about 1e-4.
+            assert!((simulated - expected).abs() <= 1e-4 * expected + 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_offset_shifts_response() {
+        let centred = SimulatedRig::default();
+        let offset = SimulatedRig {
+            zeropoint_offset: 2.5,
+            ..Default::default()
+        };
+
+        for &raw in &[170., 175., 185., 190.] {
+            let a = centred.expected_flux(raw).unwrap();
+            let b = offset.expected_flux(raw + 2.5).unwrap();
+            assert!((a - b).abs() < 1e-9 * a.max(1.));
+        }
+    }
+}
@@ -0,0 +1,533 @@
+//! Source spectra made of emission lines, continua and tabulated components, for integrating
+//! through a filter. `SourceSpectrum::laser` covers the common case of a single Gaussian laser
+//! line.
+
+use std::path::Path;
+
+use compute::prelude::{interp1d_linear, trapezoid, ExtrapolationMode};
+use csv::Reader;
+use serde::{Deserialize, Serialize};
+
+use super::transmission::{shifted_transmission, Filter, FilterSpec, Wavefront};
+use crate::{
+    error::{Error, Result},
+    utils::cmp_nan_last,
+};
+
+/// Ratio of the FWHM of a Gaussian to its standard deviation.
+const GAUSSIAN_FWHM: f64 = 2.3548200450309493;
+/// Spacing, in nm, of the grid between lines.
+const BASE_STEP: f64 = 0.05;
+/// Number of grid points per line FWHM near a line.
+const POINTS_PER_FWHM: f64 = 20.;
+/// How many FWHMs either side of a line the fine grid extends.
+const LINE_HALF_WIDTH: f64 = 10.;
+
+/// Shape of an emission line. Every profile has unit area.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LineProfile {
+    Gaussian {
+        fwhm: f64,
+    },
+    Lorentzian {
+        fwhm: f64,
+    },
+    /// Pseudo-Voigt approximation (Thompson, Cox & Hastings 1987) to the convolution of a
+    /// Gaussian and a Lorentzian.
+    Voigt {
+        gaussian_fwhm: f64,
+        lorentzian_fwhm: f64,
+    },
+}
+
+impl LineProfile {
+    /// Full width at half maximum, in nm.
+    pub fn fwhm(&self) -> f64 {
+        match *self {
+            LineProfile::Gaussian { fwhm } | LineProfile::Lorentzian { fwhm } => fwhm,
+            LineProfile::Voigt {
+                gaussian_fwhm: g,
+                lorentzian_fwhm: l,
+            } => (g.powi(5)
+                + 2.69269 * g.powi(4) * l
+                + 2.42843 * g.powi(3) * l.powi(2)
+                + 4.47163 * g.powi(2) * l.powi(3)
+                + 0.07842 * g * l.powi(4)
+                + l.powi(5))
+            .powf(0.2),
+        }
+    }
+
+    /// Profile density at an offset `dx` nm from the line centre.
+    pub fn density(&self, dx: f64) -> f64 {
+        match *self {
+            LineProfile::Gaussian { fwhm } => gaussian(dx, fwhm),
+            LineProfile::Lorentzian { fwhm } => lorentzian(dx, fwhm),
+            LineProfile::Voigt {
+                lorentzian_fwhm, ..
+            } => {
+                let fwhm = self.fwhm();
+                let r = lorentzian_fwhm / fwhm;
+                let eta = 1.36603 * r - 0.47719 * r * r + 0.11116 * r * r * r;
+                eta * lorentzian(dx, fwhm) + (1. - eta) * gaussian(dx, fwhm)
+            }
+        }
+    }
+}
+
+fn gaussian(dx: f64, fwhm: f64) -> f64 {
+    let sigma = fwhm / GAUSSIAN_FWHM;
+    (-0.5 * (dx / sigma).powi(2)).exp() / (sigma * (2. * std::f64::consts::PI).sqrt())
+}
+
+fn lorentzian(dx: f64, fwhm: f64) -> f64 {
+    let gamma = fwhm / 2.;
+    gamma / (std::f64::consts::PI * (dx * dx + gamma * gamma))
+}
+
+/// An emission line with a given integrated flux.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpectralLine {
+    /// Central wavelength, in nm.
+    pub cwl: f64,
+    /// Integrated flux, relative to the other components.
+    pub strength: f64,
+    pub profile: LineProfile,
+}
+
+impl SpectralLine {
+    /// Fails unless the profile has a positive FWHM, and for a Voigt profile neither width is
+    /// negative.
+    pub fn new(cwl: f64, strength: f64, profile: LineProfile) -> Result<Self> {
+        let widths = match profile {
+            LineProfile::Voigt {
+                gaussian_fwhm,
+                lorentzian_fwhm,
+            } => gaussian_fwhm >= 0. && lorentzian_fwhm >= 0.,
+            _ => true,
+        };
+        if !(widths && profile.fwhm() > 0.) {
+            return Err(Error::Spectrum(format!(
+                "the line at {} nm needs a positive width, not {:?}",
+                cwl, profile
+            )));
+        }
+        Ok(SpectralLine {
+            cwl,
+            strength,
+            profile,
+        })
+    }
+}
+
+/// A smooth continuum, in flux per nm.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Continuum {
+    Flat {
+        level: f64,
+    },
+    /// `level * (wavelength / reference)^index`.
+    PowerLaw {
+        level: f64,
+        reference: f64,
+        index: f64,
+    },
+}
+
+impl Continuum {
+    pub fn density(&self, wavelength: f64) -> f64 {
+        match *self {
+            Continuum::Flat { level } => level,
+            Continuum::PowerLaw {
+                level,
+                reference,
+                index,
+            } => level * (wavelength / reference).powf(index),
+        }
+    }
+}
+
+/// A spectrum sampled at increasing wavelengths, in flux per nm. Zero outside its range.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TabulatedSpectrum {
+    pub wavelength: Vec<f64>,
+    pub flux: Vec<f64>,
+}
+
+impl TabulatedSpectrum {
+    /// Read a spectrum from a CSV with `wavelength` (nm) and `flux` columns. Like
+    /// `load_transmission_data`, this fails unless there is at least one row, every value is
+    /// finite and the wavelengths increase strictly.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Row {
+            wavelength: f64,
+            flux: f64,
+        }
+
+        let path = path.as_ref();
+        let invalid = |line: u64, issue: String| {
+            Error::Spectrum(format!("{} line {}: {}", path.display(), line, issue))
+        };
+
+        let mut spectrum = TabulatedSpectrum {
+            wavelength: Vec::new(),
+            flux: Vec::new(),
+        };
+        let mut rdr = Reader::from_path(path)?;
+        for result in rdr.deserialize() {
+            let row: Row = result?;
+            // The header is line 1.
+            let line = spectrum.wavelength.len() as u64 + 2;
+            if !row.wavelength.is_finite() || !row.flux.is_finite() {
+                return Err(invalid(
+                    line,
+                    format!(
+                        "wavelength {} and flux {} must be finite",
+                        row.wavelength, row.flux
+                    ),
+                ));
+            }
+            if let Some(&previous) = spectrum.wavelength.last() {
+                if row.wavelength <= previous {
+                    return Err(invalid(
+                        line,
+                        format!(
+                            "wavelength {} does not increase from the previous {}",
+                            row.wavelength, previous
+                        ),
+                    ));
+                }
+            }
+            spectrum.wavelength.push(row.wavelength);
+            spectrum.flux.push(row.flux);
+        }
+
+        if spectrum.wavelength.is_empty() {
+            return Err(Error::Spectrum(format!(
+                "{} has no rows of data",
+                path.display()
+            )));
+        }
+        Ok(spectrum)
+    }
+
+    pub fn density(&self, wavelength: f64) -> f64 {
+        interp1d_linear(
+            &self.wavelength,
+            &self.flux,
+            &[wavelength],
+            ExtrapolationMode::Fill(0., 0.),
+        )[0]
+    }
+}
+
+/// A source spectrum: the sum of emission lines, continua and tabulated spectra.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceSpectrum {
+    pub lines: Vec<SpectralLine>,
+    pub continua: Vec<Continuum>,
+    pub tabulated: Vec<TabulatedSpectrum>,
+}
+
+impl SourceSpectrum {
+    pub fn new() -> Self {
+        SourceSpectrum::default()
+    }
+
+    /// A single Gaussian laser line of unit strength.
+    pub fn laser(cwl: f64, fwhm: f64) -> Result<Self> {
+        SourceSpectrum::lasers(&[cwl], fwhm)
+    }
+
+    /// Gaussian laser lines of unit strength and a common FWHM, one at each of `cwls`.
+    pub fn lasers(cwls: &[f64], fwhm: f64) -> Result<Self> {
+        cwls.iter().try_fold(SourceSpectrum::new(), |source, &cwl| {
+            source.line(cwl, 1., LineProfile::Gaussian { fwhm })
+        })
+    }
+
+    /// Add a line; see `SpectralLine::new`.
+    pub fn line(mut self, cwl: f64, strength: f64, profile: LineProfile) -> Result<Self> {
+        self.lines.push(SpectralLine::new(cwl, strength, profile)?);
+        Ok(self)
+    }
+
+    pub fn continuum(mut self, continuum: Continuum) -> Self {
+        self.continua.push(continuum);
+        self
+    }
+
+    pub fn tabulated(mut self, tabulated: TabulatedSpectrum) -> Self {
+        self.tabulated.push(tabulated);
+        self
+    }
+
+    /// Flux per nm at a wavelength.
+    pub fn density(&self, wavelength: f64) -> f64 {
+        self.lines
+            .iter()
+            .map(|l| l.strength * l.profile.density(wavelength - l.cwl))
+            .chain(self.continua.iter().map(|c| c.density(wavelength)))
+            .chain(self.tabulated.iter().map(|t| t.density(wavelength)))
+            .sum()
+    }
+
+    /// Wavelengths between `lo` and `hi` to sample the spectrum at: a coarse grid, refined
+    /// around each line in proportion to its width, plus the points of any tabulated spectra.
+    pub fn grid(&self, lo: f64, hi: f64) -> Vec<f64> {
+        let mut grid = Vec::new();
+        let mut push_range = |from: f64, to: f64, step: f64| {
+            let (from, to) = (from.max(lo), to.min(hi));
+            if from > to {
+                return;
+            }
+            let n = ((to - from) / step).ceil() as usize;
+            grid.extend((0..=n).map(|i| (from + i as f64 * step).min(to)));
+        };
+
+        push_range(lo, hi, BASE_STEP);
+        for line in &self.lines {
+            let fwhm = line.profile.fwhm();
+            // Lines built directly rather than through `SpectralLine::new` may have no width,
+            // which would never finish refining.
+            if !(fwhm > 0.) {
+                continue;
+            }
+            push_range(
+                line.cwl - LINE_HALF_WIDTH * fwhm,
+                line.cwl + LINE_HALF_WIDTH * fwhm,
+                fwhm / POINTS_PER_FWHM,
+            );
+        }
+        grid.extend(
+            self.tabulated
+                .iter()
+                .flat_map(|t| t.wavelength.iter().cloned())
+                .filter(|&w| w >= lo && w <= hi),
+        );
+
+        grid.sort_by(cmp_nan_last);
+        grid.dedup_by(|a, b| (*a - *b).abs() < 1e-9);
+        grid
+    }
+
+    /// The union of a transmission curve's wavelengths and the adaptive grid of the spectrum,
+    /// with the transmission on it. `None` for an empty curve.
+    fn sample(&self, t_wavelength: &[f64], t_flux: &[f64]) -> Option<(Vec<f64>, Vec<f64>)> {
+        let (lo, hi) = match (t_wavelength.first(), t_wavelength.last()) {
+            (Some(&lo), Some(&hi)) => (lo, hi),
+            _ => return None,
+        };
+
+        let mut grid = self.grid(lo, hi);
+        grid.extend_from_slice(t_wavelength);
+        grid.sort_by(cmp_nan_last);
+        grid.dedup_by(|a, b| (*a - *b).abs() < 1e-9);
+
+        let transmission =
+            interp1d_linear(t_wavelength, t_flux, &grid, ExtrapolationMode::Fill(0., 0.)).to_vec();
+        Some((grid, transmission))
+    }
+
+    /// Flux integrated over a transmission curve, on the union of the curve's wavelengths and
+    /// the adaptive grid of the spectrum.
+    pub fn integrate(&self, t_wavelength: &[f64], t_flux: &[f64]) -> f64 {
+        match self.sample(t_wavelength, t_flux) {
+            Some((grid, transmission)) => {
+                integrate_sampled(&grid, &transmission, |w| self.density(w))
+            }
+            None => 0.,
+        }
+    }
+
+    /// Flux of each line, scaled by its strength, integrated over a transmission curve on the
+    /// grid `integrate` uses for the whole spectrum. Continua and tabulated spectra are left
+    /// out, so for a spectrum of lines alone these add up to `integrate`.
+    pub fn integrate_lines(&self, t_wavelength: &[f64], t_flux: &[f64]) -> Vec<f64> {
+        match self.sample(t_wavelength, t_flux) {
+            Some((grid, transmission)) => self
+                .lines
+                .iter()
+                .map(|l| {
+                    integrate_sampled(&grid, &transmission, |w| {
+                        l.strength * l.profile.density(w - l.cwl)
+                    })
+                })
+                .collect(),
+            None => vec![0.; self.lines.len()],
+        }
+    }
+}
+
+/// Trapezoid integral of `transmission` times `density` over `grid`.
+fn integrate_sampled(grid: &[f64], transmission: &[f64], density: impl Fn(f64) -> f64) -> f64 {
+    let y = grid
+        .iter()
+        .zip(transmission)
+        .map(|(&w, t)| t * density(w))
+        .collect::<Vec<_>>();
+    trapezoid(&y, Some(grid), None)
+}
+
+/// Flux from `source` transmitted by `filter` with its passband shifted by `filtershift` nm; see
+/// `shifted_transmission`.
+pub fn integrate_spectrum(
+    filter: Filter,
+    spec: &FilterSpec,
+    filtershift: Option<f64>,
+    wavefront: Wavefront,
+    source: &SourceSpectrum,
+) -> Result<f64> {
+    let (t_wavelength, t_flux) = shifted_transmission(filter, spec, filtershift, wavefront)?;
+    Ok(source.integrate(&t_wavelength, &t_flux))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_profiles_have_unit_area() {
+        for profile in &[
+            LineProfile::Gaussian { fwhm: 0.5 },
+            LineProfile::Lorentzian { fwhm: 0.5 },
+            LineProfile::Voigt {
+                gaussian_fwhm: 0.4,
+                lorentzian_fwhm: 0.2,
+            },
+        ] {
+            let source = SourceSpectrum::new().line(656., 1., *profile).unwrap();
+            let grid = source.grid(556., 756.);
+            let y = grid.iter().map(|&w| source.density(w)).collect::<Vec<_>>();
+            let area = trapezoid(&y, Some(&grid), None);
+            assert!((area - 1.).abs() < 0.01, "{:?}: {}", profile, area);
+        }
+
+        let voigt = LineProfile::Voigt {
+            gaussian_fwhm: 0.5,
+            lorentzian_fwhm: 0.,
+        };
+        let gaussian = LineProfile::Gaussian { fwhm: 0.5 };
+        assert!((voigt.density(0.1) - gaussian.density(0.1)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_rejects_lines_without_width() {
+        for profile in &[
+            LineProfile::Gaussian { fwhm: 0. },
+            LineProfile::Lorentzian { fwhm: 0. },
+            LineProfile::Lorentzian { fwhm: f64::NAN },
+            LineProfile::Voigt {
+                gaussian_fwhm: 0.,
+                lorentzian_fwhm: 0.,
+            },
+            LineProfile::Voigt {
+                gaussian_fwhm: 0.5,
+                lorentzian_fwhm: -0.1,
+            },
+        ] {
+            match SourceSpectrum::new().line(656., 1., *profile) {
+                Err(Error::Spectrum(_)) => {}
+                other => panic!("{:?}: {:?}", profile, other),
+            }
+        }
+        assert!(SourceSpectrum::laser(656.3, 0.).is_err());
+    }
+
+    #[test]
+    fn test_laser_matches_fine_grid() {
+        let spec = FilterSpec::nominal(Filter::Bpf31Deg0).unwrap();
+        let source = SourceSpectrum::laser(656.3, 0.61).unwrap();
+        let fine = (0..20_000)
+            .map(|i| 650. + i as f64 * 0.001)
+            .collect::<Vec<_>>();
+        for &shift in &[0., -2., -4.] {
+            let (wavelength, transmission) =
+                shifted_transmission(Filter::Bpf31Deg0, &spec, Some(shift), Wavefront::TCOLL)
+                    .unwrap();
+            let on_fine = interp1d_linear(
+                &wavelength,
+                &transmission,
+                &fine,
+                ExtrapolationMode::Fill(0., 0.),
+            );
+            let expected = integrate_sampled(&fine, &on_fine, |w| source.density(w));
+            let flux = source.integrate(&wavelength, &transmission);
+            assert!((flux - expected).abs() < 1e-4 * expected.max(1e-3));
+        }
+    }
+
+    #[test]
+    fn test_lines_share_one_grid() {
+        let spec = FilterSpec::nominal(Filter::Bpf31Deg0).unwrap();
+        let (wavelength, transmission) =
+            shifted_transmission(Filter::Bpf31Deg0, &spec, Some(-1.), Wavefront::TCOLL).unwrap();
+        let source = SourceSpectrum::lasers(&[656.3, 658.5], 0.61)
+            .unwrap()
+            .line(657.5, 0.5, LineProfile::Lorentzian { fwhm: 0.2 })
+            .unwrap();
+
+        let lines = source.integrate_lines(&wavelength, &transmission);
+        let total = source.integrate(&wavelength, &transmission);
+        assert_eq!(lines.len(), 3);
+        assert!((lines.iter().sum::<f64>() - total).abs() < 1e-12 * total);
+        for (line, flux) in source.lines.iter().zip(&lines) {
+            let alone = SourceSpectrum::new()
+                .line(line.cwl, line.strength, line.profile)
+                .unwrap()
+                .integrate(&wavelength, &transmission);
+            assert!((flux - alone).abs() < 1e-4 * alone.max(1e-3));
+        }
+    }
+
+    #[test]
+    fn test_lines_and_continuum_add() {
+        let spec = FilterSpec::nominal(Filter::Bpf31Deg0).unwrap();
+        let integrate = |source: &SourceSpectrum| {
+            integrate_spectrum(Filter::Bpf31Deg0, &spec, None, Wavefront::TCOLL, source).unwrap()
+        };
+
+        let halpha = SourceSpectrum::laser(658., 0.3).unwrap();
+        let continuum = SourceSpectrum::new().continuum(Continuum::Flat { level: 0.1 });
+        let both = halpha.clone().continuum(Continuum::Flat { level: 0.1 });
+        let sum = integrate(&halpha) + integrate(&continuum);
+        assert!((integrate(&both) - sum).abs() < 1e-3 * sum);
+
+        let tabulated = SourceSpectrum::new().tabulated(TabulatedSpectrum {
+            wavelength: vec![600., 700.],
+            flux: vec![0.1, 0.1],
+        });
+        let flat = integrate(&continuum);
+        assert!((integrate(&tabulated) - flat).abs() < 1e-6 * flat);
+    }
+
+    #[test]
+    fn test_invalid_tabulated_spectra() {
+        let dir = std::env::temp_dir().join(format!("dragonfly-spectrum-{}", alea::u32()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("spectrum.csv");
+
+        std::fs::write(&path, "wavelength,flux\n650,0.1\n660,0.2\n").unwrap();
+        let spectrum = TabulatedSpectrum::from_file(&path).unwrap();
+        assert_eq!(spectrum.wavelength, vec![650., 660.]);
+
+        for (rows, line) in &[
+            ("", None),
+            ("650,0.1\n650,0.2", Some(3)),
+            ("650,0.1\n640,0.2", Some(3)),
+            ("650,NaN", Some(2)),
+            ("650,0.1\ninf,0.2", Some(3)),
+        ] {
+            std::fs::write(&path, format!("wavelength,flux\n{}\n", rows)).unwrap();
+            match TabulatedSpectrum::from_file(&path) {
+                Err(Error::Spectrum(msg)) => {
+                    if let Some(line) = line {
+                        assert!(msg.contains(&format!("line {}:", line)), "{}", msg);
+                    }
+                }
+                other => panic!("{:?}: {:?}", rows, other),
+            }
+        }
+    }
+}
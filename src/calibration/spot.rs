@@ -0,0 +1,289 @@
+//! Picking the laser spot out of the objects SExtractor finds in a calibration frame. Each
+//! object is scored by its distance from where the spot has been, its size relative to the spot
+//! so far and the change in flux since the previous frame; the best-scoring object is taken, and
+//! the choice is flagged as ambiguous if another object scores almost as well.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sextractor::CatalogObject,
+    utils::{cmp_nan_last, quantile},
+};
+
+/// The catalog object taken as the laser spot in a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpotChoice {
+    /// SExtractor's running number of the object.
+    pub number: usize,
+    /// Position of the object, in pixels.
+    pub x: f64,
+    pub y: f64,
+    pub flux: f64,
+    pub area: f64,
+    /// Score of the object; lower is better, and 0 is a perfect match.
+    pub cost: f64,
+    /// Number of objects considered.
+    pub ncandidates: usize,
+    /// Whether another object scored within `SpotFinder::ambiguity_margin` of this one.
+    pub ambiguous: bool,
+}
+
+/// Identifies the laser spot across the frames of a calibration sweep. Unambiguous choices are
+/// remembered, and once `learn_frames` of them have been made, objects further than
+/// `max_offset` from the spot's usual position are no longer considered. The flux is compared
+/// with the choice in the previous frame, ambiguous or not.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpotFinder {
+    /// Number of unambiguous choices after which the spot position is trusted.
+    pub learn_frames: usize,
+    /// Furthest, in pixels, the spot may be from its usual position once learned.
+    pub max_offset: f64,
+    /// Factor by which the spot area may differ from its usual area at a cost of 1.
+    pub size_tolerance: f64,
+    /// Factor by which the spot flux may change between frames at a cost of 1. The flux changes
+    /// a lot with tilt on the edges of the passband, so this is generous.
+    pub flux_tolerance: f64,
+    /// Smallest difference in cost between the best and second-best objects for a choice to be
+    /// unambiguous.
+    pub ambiguity_margin: f64,
+    history: Vec<SpotChoice>,
+    previous: Option<SpotChoice>,
+}
+
+impl Default for SpotFinder {
+    fn default() -> Self {
+        SpotFinder {
+            learn_frames: 3,
+            max_offset: 20.,
+            size_tolerance: 2.,
+            flux_tolerance: 10.,
+            ambiguity_margin: 1.,
+            history: Vec::new(),
+            previous: None,
+        }
+    }
+}
+
+fn median(values: impl Iterator<Item = f64>) -> f64 {
+    let mut sorted = values.collect::<Vec<_>>();
+    sorted.sort_by(cmp_nan_last);
+    quantile(&sorted, 0.5)
+}
+
+/// Squared log ratio of `a` to `b`, in units of `ln(tolerance)`.
+fn log_cost(a: f64, b: f64, tolerance: f64) -> f64 {
+    ((a.max(f64::MIN_POSITIVE) / b.max(f64::MIN_POSITIVE)).ln() / tolerance.ln()).powi(2)
+}
+
+impl SpotFinder {
+    pub fn new() -> Self {
+        SpotFinder::default()
+    }
+
+    pub fn learn_frames(mut self, learn_frames: usize) -> Self {
+        self.learn_frames = learn_frames;
+        self
+    }
+
+    pub fn max_offset(mut self, max_offset: f64) -> Self {
+        self.max_offset = max_offset;
+        self
+    }
+
+    pub fn ambiguity_margin(mut self, ambiguity_margin: f64) -> Self {
+        self.ambiguity_margin = ambiguity_margin;
+        self
+    }
+
+    /// Usual position of the spot, from the unambiguous choices so far.
+    pub fn expected_position(&self) -> Option<(f64, f64)> {
+        if self.history.is_empty() {
+            return None;
+        }
+        Some((
+            median(self.history.iter().map(|c| c.x)),
+            median(self.history.iter().map(|c| c.y)),
+        ))
+    }
+
+    /// Whether enough frames have been seen to trust the spot position.
+    pub fn is_learned(&self) -> bool {
+        self.history.len() >= self.learn_frames
+    }
+
+    /// Unambiguous choices made so far, in order.
+    pub fn history(&self) -> &[SpotChoice] {
+        &self.history
+    }
+
+    /// Score an object; `None` if it is too far from the learned spot position.
+    fn cost(&self, object: &CatalogObject, largest_area: f64) -> Option<f64> {
+        let position = match self.expected_position() {
+            Some((x, y)) => {
+                let offset = (object.x_image - x).hypot(object.y_image - y);
+                if self.is_learned() && offset > self.max_offset {
+                    return None;
+                }
+                (offset / self.max_offset).powi(2)
+            }
+            None => 0.,
+        };
+
+        // Until there is a spot to compare with, prefer the largest object.
+        let size = if self.history.is_empty() {
+            log_cost(object.area, largest_area, self.size_tolerance)
+        } else {
+            log_cost(
+                object.area,
+                median(self.history.iter().map(|c| c.area)),
+                self.size_tolerance,
+            )
+        };
+        let flux = self.previous.map_or(0., |previous| {
+            log_cost(object.flux, previous.flux, self.flux_tolerance)
+        });
+
+        Some(position + size + flux)
+    }
+
+    /// Choose the spot among the objects detected in a frame, remembering the choice if it is
+    /// unambiguous. Returns `None` if there are no objects, or none near the learned position.
+    pub fn choose(&mut self, objects: &[CatalogObject]) -> Option<SpotChoice> {
+        let largest_area = objects.iter().map(|o| o.area).fold(0., f64::max);
+        let mut scored = objects
+            .iter()
+            .filter_map(|o| self.cost(o, largest_area).map(|cost| (o, cost)))
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| cmp_nan_last(&a.1, &b.1));
+
+        let (best, cost) = *scored.first()?;
+        let choice = SpotChoice {
+            number: best.number,
+            x: best.x_image,
+            y: best.y_image,
+            flux: best.flux,
+            area: best.area,
+            cost,
+            ncandidates: objects.len(),
+            ambiguous: scored
+                .get(1)
+                .map_or(false, |&(_, next)| next - cost < self.ambiguity_margin),
+        };
+        self.record(choice);
+        Some(choice)
+    }
+
+    /// Remember a choice, e.g. one made before a run was resumed, as the spot in the latest
+    /// frame. Its position and size are learned from only if it is unambiguous.
+    pub fn record(&mut self, choice: SpotChoice) {
+        if !choice.ambiguous {
+            self.history.push(choice);
+        }
+        self.previous = Some(choice);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sextractor::deserialize_sextractor;
+
+    /// A catalog line with the given number, position, flux and area.
+    fn object(number: usize, x: f64, y: f64, flux: f64, area: f64) -> String {
+        format!(
+            "{} {} {} 0 0 1 1 {} 0 3.0 -10.0 {} 1.0 100.0",
+            number, x, y, flux, area
+        )
+    }
+
+    fn catalog(lines: &[String]) -> Vec<CatalogObject> {
+        deserialize_sextractor(&lines.join("\n")).unwrap()
+    }
+
+    #[test]
+    fn test_learns_spot_and_rejects_interlopers() {
+        let mut finder = SpotFinder::new();
+
+        // A hot pixel elsewhere in the frame: the larger object is taken.
+        for (i, &(x, y)) in [(500., 500.), (501., 499.), (499., 501.)]
+            .iter()
+            .enumerate()
+        {
+            let choice = finder
+                .choose(&catalog(&[
+                    object(1, 800., 200., 500., 2.),
+                    object(2, x, y, 1e5 * (i + 1) as f64, 100.),
+                ]))
+                .unwrap();
+            assert_eq!(choice.number, 2);
+            assert!(!choice.ambiguous);
+        }
+        assert!(finder.is_learned());
+        assert_eq!(finder.expected_position(), Some((500., 500.)));
+
+        // A satellite trail far from the spot is ignored, even though it is much bigger.
+        let choice = finder
+            .choose(&catalog(&[
+                object(1, 100., 900., 1e6, 400.),
+                object(2, 501., 499., 2e4, 60.),
+            ]))
+            .unwrap();
+        assert_eq!((choice.number, choice.ncandidates), (2, 2));
+
+        assert!(finder
+            .choose(&catalog(&[object(1, 100., 900., 1e6, 400.)]))
+            .is_none());
+        assert!(finder.choose(&[]).is_none());
+    }
+
+    #[test]
+    fn test_flags_ambiguous_frames() {
+        let mut finder = SpotFinder::new();
+        for _ in 0..3 {
+            finder.choose(&catalog(&[object(1, 500., 500., 1e5, 100.)]));
+        }
+
+        let choice = finder
+            .choose(&catalog(&[
+                object(1, 495., 503., 9e4, 90.),
+                object(2, 502., 500., 1e5, 100.),
+            ]))
+            .unwrap();
+        assert_eq!(choice.number, 2);
+        assert!(choice.ambiguous);
+        assert_eq!(finder.history().len(), 3);
+    }
+
+    #[test]
+    fn test_resumes_from_recorded_choices() {
+        let spot = |number, flux, ambiguous| SpotChoice {
+            number,
+            x: 500.,
+            y: 500.,
+            flux,
+            area: 100.,
+            cost: 0.,
+            ncandidates: 1,
+            ambiguous,
+        };
+        let mut finder = SpotFinder::new();
+        for number in 1..=3 {
+            finder.record(spot(number, 1e5, false));
+        }
+        assert!(finder.is_learned());
+
+        // The spot dimmed on the edge of the passband, in a frame that was ambiguous.
+        finder.record(spot(4, 1e3, true));
+        assert_eq!(finder.history().len(), 3);
+
+        // The flux is compared with that frame, not the last unambiguous one.
+        let choice = finder
+            .choose(&catalog(&[
+                object(1, 500., 500., 1e5, 100.),
+                object(2, 501., 500., 1.2e3, 100.),
+            ]))
+            .unwrap();
+        assert_eq!(choice.number, 2);
+        assert!(!choice.ambiguous);
+    }
+}
@@ -0,0 +1,274 @@
+//! Raw angles to measure in a calibration sweep. Besides evenly spaced angles, an adaptive sweep
+//! takes a coarse pass and then adds angles where the model flux changes fastest with tilt (the
+//! edges of the transmission curve), since the flat top and the wings say little about the
+//! tilt shift.
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    fit::{tilt_model, TiltFit},
+    model::TransmissionCurves,
+    uncertainty::TiltFitUncertainty,
+};
+use crate::utils::{cmp_nan_last, round_to_digits};
+
+/// Spacing, in degrees, of the candidate angles considered for refinement.
+const CANDIDATE_STEP: f64 = 0.1;
+/// Step, in degrees, of the central difference used for the model slope.
+const SLOPE_STEP: f64 = 0.05;
+
+/// `nstep` evenly spaced raw angles from `start` to `end`, rounded to 0.1 degrees. A single step
+/// is just `start`, and no steps no angles.
+pub fn uniform_angles(start: f64, end: f64, nstep: usize) -> Vec<f64> {
+    let stepsize = (end - start) / (nstep.max(2) - 1) as f64;
+    (0..nstep)
+        .map(|x| round_to_digits(start + x as f64 * stepsize, 1))
+        .collect()
+}
+
+/// Magnitude of the slope, per degree, of the normalized model flux at the given raw angles for
+/// the fitted NII fraction and tilt shift.
+pub fn model_slope(curves: &TransmissionCurves, fit: &TiltFit, raw_angles: &[f64]) -> Vec<f64> {
+    let tilts = |offset: f64| {
+        raw_angles
+            .iter()
+            .map(|a| a - 180. - fit.tilt_shift + offset)
+            .collect::<Vec<_>>()
+    };
+    let above = tilt_model(curves, fit.nii_fraction, &tilts(SLOPE_STEP));
+    let below = tilt_model(curves, fit.nii_fraction, &tilts(-SLOPE_STEP));
+    above
+        .iter()
+        .zip(&below)
+        .map(|(a, b)| ((a - b) / (2. * SLOPE_STEP)).abs())
+        .collect()
+}
+
+/// A sweep that refines a coarse pass on the edges of the transmission curve until the tilt
+/// shift is known well enough.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AdaptiveSweep {
+    /// Lowest raw angle to measure.
+    pub start: f64,
+    /// Highest raw angle to measure.
+    pub end: f64,
+    /// Number of evenly spaced angles in the coarse pass. At least two are taken, the fewest a
+    /// fit needs.
+    pub ncoarse: usize,
+    /// Number of angles added per refinement step.
+    pub batch: usize,
+    /// Largest total number of angles to measure.
+    pub max_steps: usize,
+    /// Standard error of the tilt shift, in degrees, at which to stop.
+    pub target_uncertainty: f64,
+    /// Closest, in degrees, a new angle may be to one already measured.
+    pub min_separation: f64,
+}
+
+impl AdaptiveSweep {
+    pub fn new(start: f64, end: f64) -> Self {
+        AdaptiveSweep {
+            start,
+            end,
+            ncoarse: 10,
+            batch: 4,
+            max_steps: 60,
+            target_uncertainty: 0.02,
+            min_separation: 0.2,
+        }
+    }
+
+    pub fn ncoarse(mut self, ncoarse: usize) -> Self {
+        self.ncoarse = ncoarse;
+        self
+    }
+
+    pub fn batch(mut self, batch: usize) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    pub fn max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn target_uncertainty(mut self, target_uncertainty: f64) -> Self {
+        self.target_uncertainty = target_uncertainty;
+        self
+    }
+
+    pub fn min_separation(mut self, min_separation: f64) -> Self {
+        self.min_separation = min_separation;
+        self
+    }
+
+    /// Angles of the coarse pass.
+    pub fn coarse_angles(&self) -> Vec<f64> {
+        uniform_angles(self.start, self.end, self.ncoarse.max(2))
+    }
+
+    /// The next angles to measure: those with the steepest model slope at the current fit, at
+    /// least `min_separation` from every angle already tried and from each other. Empty once
+    /// `max_steps` angles have been tried or no candidate has any slope.
+    pub fn refine_angles(
+        &self,
+        curves: &TransmissionCurves,
+        fit: &TiltFit,
+        tried: &[f64],
+    ) -> Vec<f64> {
+        let nleft = self.max_steps.saturating_sub(tried.len()).min(self.batch);
+        let ncandidates = ((self.end - self.start) / CANDIDATE_STEP).floor() as usize + 1;
+        let candidates = (0..ncandidates)
+            .map(|i| round_to_digits(self.start + i as f64 * CANDIDATE_STEP, 1))
+            .filter(|&a| a <= self.end)
+            .collect::<Vec<_>>();
+
+        let mut ranked = candidates
+            .iter()
+            .cloned()
+            .zip(model_slope(curves, fit, &candidates))
+            .filter(|&(_, slope)| slope > 0.)
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| cmp_nan_last(&b.1, &a.1));
+
+        let mut chosen: Vec<f64> = Vec::with_capacity(nleft);
+        for (angle, _) in ranked {
+            if chosen.len() >= nleft {
+                break;
+            }
+            let clear = tried
+                .iter()
+                .chain(&chosen)
+                .all(|a| (a - angle).abs() >= self.min_separation);
+            if clear {
+                chosen.push(angle);
+            }
+        }
+        chosen
+    }
+
+    /// Whether the sweep can stop, given the uncertainty of the latest fit and the number of
+    /// angles tried so far.
+    pub fn is_done(&self, uncertainty: &TiltFitUncertainty, ntried: usize) -> bool {
+        uncertainty.tilt_shift.std_err <= self.target_uncertainty || ntried >= self.max_steps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calibration::{
+        fit::fit_tilt_calibration, simulation::SimulatedRig, uncertainty::jacobian_uncertainty,
+    };
+
+    #[test]
+    fn test_few_steps() {
+        assert!(uniform_angles(160., 200., 0).is_empty());
+        assert_eq!(uniform_angles(160., 200., 1), vec![160.]);
+        assert_eq!(uniform_angles(160., 200., 3), vec![160., 180., 200.]);
+        let sweep = AdaptiveSweep::new(160., 200.).ncoarse(1);
+        assert_eq!(sweep.coarse_angles(), vec![160., 200.]);
+    }
+
+    #[test]
+    fn test_refines_on_edges() {
+        let rig = SimulatedRig {
+            zeropoint_offset: 2.,
+            nii_fraction: 0.4,
+            ..Default::default()
+        };
+        let curves = TransmissionCurves::default();
+        let sweep = AdaptiveSweep::new(160., 200.);
+
+        let tried = sweep.coarse_angles();
+        assert_eq!(tried, uniform_angles(160., 200., 10));
+        let datatilt = tried.iter().map(|a| a - 180.).collect::<Vec<_>>();
+        let dataflux = tried
+            .iter()
+            .map(|&a| rig.expected_flux(a).unwrap())
+            .collect::<Vec<_>>();
+        let fit = fit_tilt_calibration(&curves, &datatilt, &dataflux, None).unwrap();
+
+        let next = sweep.refine_angles(&curves, &fit, &tried);
+        assert_eq!(next.len(), sweep.batch);
+
+        let grid = uniform_angles(160., 200., 401);
+        let steepest = model_slope(&curves, &fit, &grid)
+            .into_iter()
+            .fold(0., f64::max);
+        for (angle, slope) in next.iter().zip(model_slope(&curves, &fit, &next)) {
+            assert!(slope > 0.25 * steepest, "{} {}", angle, slope);
+            assert!(tried
+                .iter()
+                .chain(next.iter().filter(|&a| a != angle))
+                .all(|a| (a - angle).abs() >= sweep.min_separation));
+        }
+
+        let full = vec![170.; sweep.max_steps];
+        assert!(sweep.refine_angles(&curves, &fit, &full).is_empty());
+    }
+
+    /// Fit the rig's fluxes at the given raw angles, with a fixed pseudo-random read noise of
+    /// `sigma` counts so that the result doesn't depend on the run.
+    fn fit_sweep(
+        curves: &TransmissionCurves,
+        rig: &SimulatedRig,
+        sigma: f64,
+        raw_angles: &[f64],
+    ) -> (TiltFit, TiltFitUncertainty) {
+        let datatilt = raw_angles.iter().map(|a| a - 180.).collect::<Vec<_>>();
+        let dataflux = raw_angles
+            .iter()
+            .map(|&a| {
+                let u = ((a * 12.9898).sin() * 43758.5453).rem_euclid(1.);
+                rig.expected_flux(a).unwrap() + sigma * 3_f64.sqrt() * (2. * u - 1.)
+            })
+            .collect::<Vec<_>>();
+        let dataerr = vec![sigma; raw_angles.len()];
+        let fit =
+            fit_tilt_calibration(curves, &datatilt, &dataflux, Some(dataerr.as_slice())).unwrap();
+        let uncertainty =
+            jacobian_uncertainty(curves, &datatilt, &dataflux, Some(dataerr.as_slice()), &fit);
+        (fit, uncertainty)
+    }
+
+    #[test]
+    fn test_adaptive_needs_fewer_angles() {
+        let rig = SimulatedRig {
+            zeropoint_offset: 2.,
+            nii_fraction: 0.4,
+            ..Default::default()
+        };
+        let curves = TransmissionCurves::default();
+        let peak = uniform_angles(160., 200., 401)
+            .into_iter()
+            .map(|a| rig.expected_flux(a).unwrap())
+            .fold(0., f64::max);
+        let sigma = 0.01 * peak;
+
+        // The uncertainty a uniform sweep reaches with 40 angles is the target.
+        let nuniform = 40;
+        let (_, uniform) = fit_sweep(&curves, &rig, sigma, &uniform_angles(160., 200., nuniform));
+        let sweep = AdaptiveSweep::new(160., 200.)
+            .max_steps(nuniform)
+            .target_uncertainty(uniform.tilt_shift.std_err);
+
+        let mut tried = sweep.coarse_angles();
+        loop {
+            let (fit, uncertainty) = fit_sweep(&curves, &rig, sigma, &tried);
+            if sweep.is_done(&uncertainty, tried.len()) {
+                assert!(
+                    uncertainty.tilt_shift.std_err <= sweep.target_uncertainty,
+                    "{} angles",
+                    tried.len()
+                );
+                break;
+            }
+            let next = sweep.refine_angles(&curves, &fit, &tried);
+            assert!(!next.is_empty());
+            tried.extend(next);
+        }
+        assert!(tried.len() < nuniform, "{} angles", tried.len());
+    }
+}
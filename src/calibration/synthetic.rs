@@ -0,0 +1,231 @@
+use fitsio::{
+    images::{ImageDescription, ImageType as FitsImageType},
+    FitsFile,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    utils::{poisson, randn},
+};
+
+/// Shape of the laser spot on the detector.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SpotProfile {
+    /// A circular Gaussian with the given FWHM in pixels.
+    Gaussian { fwhm: f64 },
+    /// A uniformly illuminated disk with the given radius in pixels.
+    TopHat { radius: f64 },
+}
+
+/// Detector and scene parameters for synthesizing laser calibration frames.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SyntheticFrame {
+    /// Image width in pixels.
+    pub width: usize,
+    /// Image height in pixels.
+    pub height: usize,
+    /// Spot centre along x, in pixels from the left edge.
+    pub spot_x: f64,
+    /// Spot centre along y, in pixels from the bottom edge.
+    pub spot_y: f64,
+    /// Shape of the spot.
+    pub profile: SpotProfile,
+    /// Sky background level, in counts per pixel.
+    pub sky: f64,
+    /// Gaussian read noise, in counts per pixel.
+    pub read_noise: f64,
+    /// Whether to add Poisson noise to the spot and sky.
+    pub poisson_noise: bool,
+    /// Number of hot pixels to scatter over the frame.
+    pub hot_pixels: usize,
+    /// Counts added to each hot pixel.
+    pub hot_pixel_counts: f64,
+    /// Number of cosmic ray hits on the frame.
+    pub cosmic_rays: usize,
+    /// Counts deposited per pixel along each cosmic ray track.
+    pub cosmic_ray_counts: f64,
+}
+
+impl Default for SyntheticFrame {
+    fn default() -> Self {
+        SyntheticFrame {
+            width: 256,
+            height: 256,
+            spot_x: 128.,
+            spot_y: 128.,
+            profile: SpotProfile::Gaussian { fwhm: 6. },
+            sky: 100.,
+            read_noise: 5.,
+            poisson_noise: true,
+            hot_pixels: 10,
+            hot_pixel_counts: 5000.,
+            cosmic_rays: 2,
+            cosmic_ray_counts: 2000.,
+        }
+    }
+}
+
+const GAUSSIAN_FWHM: f64 = 2.3548200450309493;
+
+impl SyntheticFrame {
+    /// Noiseless spot profile, normalized so that it sums to `flux` over the frame. Pixels are
+    /// stored row by row, starting from the bottom row.
+    pub fn spot(&self, flux: f64) -> Vec<f64> {
+        let weights = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x as f64, y as f64)))
+            .map(|(x, y)| {
+                let r2 = (x - self.spot_x).powi(2) + (y - self.spot_y).powi(2);
+                match self.profile {
+                    SpotProfile::Gaussian { fwhm } => {
+                        let sigma = fwhm / GAUSSIAN_FWHM;
+                        (-r2 / (2. * sigma * sigma)).exp()
+                    }
+                    SpotProfile::TopHat { radius } => {
+                        if r2 <= radius * radius {
+                            1.
+                        } else {
+                            0.
+                        }
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let total: f64 = weights.iter().sum();
+        if total <= 0. {
+            return vec![0.; weights.len()];
+        }
+        weights.iter().map(|w| flux * w / total).collect()
+    }
+
+    /// Render a frame with a spot of the given integrated flux, including sky, noise, hot
+    /// pixels and cosmic rays as configured. Fails for a frame without pixels.
+    pub fn render(&self, flux: f64) -> Result<Vec<f64>> {
+        let npix = self.width * self.height;
+        if npix == 0 {
+            return Err(Error::Simulation(format!(
+                "cannot render an empty {}x{} frame",
+                self.width, self.height
+            )));
+        }
+
+        let mut image = self
+            .spot(flux)
+            .into_iter()
+            .map(|counts| {
+                let expected = counts + self.sky;
+                let observed = if self.poisson_noise {
+                    poisson(expected)
+                } else {
+                    expected
+                };
+                observed + self.read_noise * randn()
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..self.hot_pixels {
+            image[alea::u32() as usize % npix] += self.hot_pixel_counts;
+        }
+
+        for _ in 0..self.cosmic_rays {
+            // A short straight track in a random direction.
+            let mut x = alea::f64() * self.width as f64;
+            let mut y = alea::f64() * self.height as f64;
+            let direction = alea::f64() * 2. * std::f64::consts::PI;
+            let length = 1 + alea::u32() % 8;
+            for _ in 0..length {
+                if x < 0. || y < 0. || x >= self.width as f64 || y >= self.height as f64 {
+                    break;
+                }
+                image[y as usize * self.width + x as usize] += self.cosmic_ray_counts;
+                x += direction.cos();
+                y += direction.sin();
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// Render a frame and write it to a FITS file, overwriting any existing file.
+    pub fn write_fits(&self, flux: f64, path: &str) -> Result<()> {
+        let image = self.render(flux)?;
+
+        let description = ImageDescription {
+            data_type: FitsImageType::Double,
+            dimensions: &[self.height, self.width],
+        };
+        let mut fptr = FitsFile::create(path)
+            .with_custom_primary(&description)
+            .overwrite()
+            .open()?;
+        let hdu = fptr.primary_hdu()?;
+        hdu.write_image(&mut fptr, &image[..])?;
+        hdu.write_key(&mut fptr, "SPOTFLUX", flux)?;
+        hdu.write_key(&mut fptr, "SPOTX", self.spot_x)?;
+        hdu.write_key(&mut fptr, "SPOTY", self.spot_y)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spot_integrates_to_flux() {
+        for &profile in &[
+            SpotProfile::Gaussian { fwhm: 4. },
+            SpotProfile::TopHat { radius: 5. },
+        ] {
+            let frame = SyntheticFrame {
+                width: 64,
+                height: 48,
+                spot_x: 30.,
+                spot_y: 20.,
+                profile,
+                ..Default::default()
+            };
+            let spot = frame.spot(12345.);
+            assert_eq!(spot.len(), 64 * 48);
+            assert!((spot.iter().sum::<f64>() - 12345.).abs() < 1e-6);
+
+            let peak = spot
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+                .unwrap()
+                .0;
+            assert_eq!(peak, 20 * 64 + 30);
+        }
+    }
+
+    #[test]
+    fn test_render_without_noise() {
+        let frame = SyntheticFrame {
+            width: 32,
+            height: 32,
+            spot_x: 16.,
+            spot_y: 16.,
+            sky: 10.,
+            read_noise: 0.,
+            poisson_noise: false,
+            hot_pixels: 0,
+            cosmic_rays: 0,
+            ..Default::default()
+        };
+        let image = frame.render(1000.).unwrap();
+        assert!((image.iter().sum::<f64>() - (1000. + 10. * 32. * 32.)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_render_empty_frame() {
+        let frame = SyntheticFrame {
+            width: 0,
+            hot_pixels: 3,
+            ..Default::default()
+        };
+        assert!(matches!(frame.render(1000.), Err(Error::Simulation(_))));
+    }
+}
@@ -1,10 +1,15 @@
-use compute::prelude::{
-    arange, interp1d_linear, trapezoid, Continuous, ExtrapolationMode, Normal, Vector,
-};
+use std::{fmt, path::Path, str::FromStr};
+
+use compute::prelude::{interp1d_linear, ExtrapolationMode, Vector};
 use csv::Reader;
-use lazy_static::lazy_static;
-use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{
+    model::TransmissionModel,
+    registry::{filter_dir, FilterRegistry, RegisteredFilter},
+    spectrum::{integrate_spectrum, SourceSpectrum},
+};
+use crate::error::{Error, Result, TransmissionIssue};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct AOIRecord {
@@ -29,137 +34,533 @@ pub enum Wavefront {
     T22,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub enum Filter {
-    Bpf08Deg0,
-    Bpf08Deg10,
-    Bpf31Deg0,
-    Bpf31Deg10,
-}
-
-const TRANSMISSION_DATA_DIR: &str = "data/FilterTransmissionCurves";
-const RFR_IDX_RATIO: f64 = 1. / 2.1;
-
-pub fn load_transmission_data(filter: Filter) -> Vec<AOIRecord> {
-    let fp = match filter {
-        Filter::Bpf08Deg0 => format!("{}/0.8BPF_0deg.csv", TRANSMISSION_DATA_DIR),
-        Filter::Bpf08Deg10 => format!("{}/0.8BPF_10deg.csv", TRANSMISSION_DATA_DIR),
-        Filter::Bpf31Deg0 => format!("{}/3.1BPF_0deg.csv", TRANSMISSION_DATA_DIR),
-        Filter::Bpf31Deg10 => format!("{}/3.1BPF_10deg.csv", TRANSMISSION_DATA_DIR),
-    };
-
-    let rdr = Reader::from_path(fp).expect("Could not open file as csv");
-    rdr.into_deserialize()
-        .map(|x| x.expect("Could not deserialize field."))
-        .collect::<Vec<AOIRecord>>()
-}
-
-lazy_static! {
-    static ref TRANSMISSION_BPF08DEG0: Vec<AOIRecord> = load_transmission_data(Filter::Bpf08Deg0);
-    static ref TRANSMISSION_BPF08DEG10: Vec<AOIRecord> = load_transmission_data(Filter::Bpf08Deg10);
-    static ref TRANSMISSION_BPF31DEG0: Vec<AOIRecord> = load_transmission_data(Filter::Bpf31Deg0);
-    static ref TRANSMISSION_BPF31DEG10: Vec<AOIRecord> = load_transmission_data(Filter::Bpf31Deg10);
-}
-
-pub fn get_transmission(filter: Filter, wavefront: Wavefront) -> (Vector, Vector) {
-    let transmission: &'static [AOIRecord] = match filter {
-        Filter::Bpf08Deg0 => &TRANSMISSION_BPF08DEG0,
-        Filter::Bpf08Deg10 => &TRANSMISSION_BPF08DEG10,
-        Filter::Bpf31Deg0 => &TRANSMISSION_BPF31DEG0,
-        Filter::Bpf31Deg10 => &TRANSMISSION_BPF31DEG10,
-    };
-
-    transmission
+/// A filter in the global `FilterRegistry`, identified by name. Filters parsed from a name are
+/// always registered; the constants for the built-in filters may not be, if their curves are
+/// missing from the filter directory, so looking them up can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Filter(&'static str);
+
+#[allow(non_upper_case_globals)]
+impl Filter {
+    pub const Bpf08Deg0: Filter = Filter("0.8BPF_0deg");
+    pub const Bpf08Deg10: Filter = Filter("0.8BPF_10deg");
+    pub const Bpf31Deg0: Filter = Filter("3.1BPF_0deg");
+    pub const Bpf31Deg10: Filter = Filter("3.1BPF_10deg");
+
+    /// Name the filter is registered under.
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+
+    /// The filter's metadata and transmission curve.
+    pub fn curve(&self) -> Result<&'static RegisteredFilter> {
+        FilterRegistry::global()
+            .get(self.0)
+            .ok_or_else(|| Error::UnknownFilter {
+                name: self.0.to_owned(),
+                dir: filter_dir().display().to_string(),
+            })
+    }
+
+    /// Bandwidth (FWHM) of the filter, in nm.
+    pub fn bandwidth(&self) -> Result<f64> {
+        Ok(self.curve()?.bandwidth())
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Serialize for Filter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Nominal effective refractive index of the filter coatings.
+pub const DEFAULT_EFFECTIVE_INDEX: f64 = 2.1;
+
+/// Nominal specification of `Filter::Bpf31Deg0`, as recorded in its sidecar. Defaults use it so
+/// that they can be built without the filter registry.
+pub const DEFAULT_FILTER_SPEC: FilterSpec = FilterSpec {
+    cwl: 659.9,
+    effective_index: DEFAULT_EFFECTIVE_INDEX,
+    bandwidth: 3.1,
+};
+
+/// Optical properties of an interference filter that set how its passband moves with tilt.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FilterSpec {
+    /// Central wavelength at normal incidence, in nm.
+    pub cwl: f64,
+    /// Effective refractive index of the coating stack.
+    pub effective_index: f64,
+    /// Bandwidth (FWHM) of the passband, in nm.
+    pub bandwidth: f64,
+}
+
+impl FilterSpec {
+    pub fn new(cwl: f64, effective_index: f64, bandwidth: f64) -> Self {
+        FilterSpec {
+            cwl,
+            effective_index,
+            bandwidth,
+        }
+    }
+
+    /// The nominal specification of a filter, from its registry metadata.
+    pub fn nominal(filter: Filter) -> Result<Self> {
+        let curve = filter.curve()?;
+        Ok(FilterSpec::new(
+            curve.metadata.cwl,
+            DEFAULT_EFFECTIVE_INDEX,
+            curve.bandwidth(),
+        ))
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    /// Look up a filter in the global registry by name, e.g. `3.1BPF_0deg`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let registry = FilterRegistry::global();
+        match registry.get(s) {
+            Some(filter) => Ok(Filter(filter.metadata.name.as_str())),
+            None => Err(format!(
+                "unknown filter {:?}; known filters are {}",
+                s,
+                registry.names().collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+}
+
+impl Wavefront {
+    /// All wavefronts, in order of increasing cone angle.
+    pub const ALL: [Wavefront; 3] = [Wavefront::TCOLL, Wavefront::T3, Wavefront::T22];
+
+    /// Cone angle of the beam, in degrees, that the curve was measured with.
+    pub fn cone_angle(&self) -> f64 {
+        match self {
+            Wavefront::TCOLL => 0.,
+            Wavefront::T3 => 3.,
+            Wavefront::T22 => 22.,
+        }
+    }
+}
+
+impl FromStr for Wavefront {
+    type Err = String;
+
+    /// Parse a wavefront from `coll`, `3deg` or `22deg`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "coll" => Ok(Wavefront::TCOLL),
+            "3deg" => Ok(Wavefront::T3),
+            "22deg" => Ok(Wavefront::T22),
+            _ => Err(format!("unknown wavefront {:?}", s)),
+        }
+    }
+}
+
+/// Columns of a transmission CSV, as (field name, alias) pairs in `AOIRecord` order.
+const TRANSMISSION_COLUMNS: [(&str, &str); 6] = [
+    ("lambda_coll", "lambdacoll"),
+    ("lambda_22deg", "lambda22deg"),
+    ("lambda_3deg", "lambda3deg"),
+    ("t_coll", "Tcoll"),
+    ("t_22deg", "T22deg"),
+    ("t_3deg", "T3deg"),
+];
+
+/// Read and validate a transmission curve CSV with the `AOIRecord` columns. Every value must be
+/// a number, transmissions must lie in [0, 1], each wavelength column must increase strictly and
+/// the wavelength columns must overlap.
+pub fn load_transmission_data<P: AsRef<Path>>(path: P) -> Result<Vec<AOIRecord>> {
+    let path = path.as_ref();
+    let invalid =
+        |row: Option<usize>, column: &str, issue: TransmissionIssue| Error::InvalidTransmission {
+            path: path.display().to_string(),
+            row,
+            column: column.to_owned(),
+            issue,
+        };
+
+    let mut rdr = Reader::from_path(path)?;
+    let headers = rdr.headers()?.clone();
+    let indices = TRANSMISSION_COLUMNS
         .iter()
-        .map(|r| {
-            (
-                r.lambda_coll,
-                match wavefront {
-                    Wavefront::TCOLL => r.t_coll,
-                    Wavefront::T3 => r.t_3deg,
-                    Wavefront::T22 => r.t_22deg,
-                },
-            )
+        .map(|(name, alias)| {
+            headers
+                .iter()
+                .position(|h| h.trim() == *name || h.trim() == *alias)
+                .ok_or_else(|| invalid(Some(1), alias, TransmissionIssue::MissingColumn))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut records: Vec<AOIRecord> = Vec::new();
+    for row in rdr.records() {
+        let row = row?;
+        let line = row.position().map(|p| p.line() as usize);
+
+        let mut values = [0.; 6];
+        for (k, &i) in indices.iter().enumerate() {
+            let column = TRANSMISSION_COLUMNS[k].1;
+            let field = row.get(i).unwrap_or("").trim();
+            let value = field
+                .parse::<f64>()
+                .map_err(|_| invalid(line, column, TransmissionIssue::Unparseable(field.into())))?;
+            if value.is_nan() {
+                return Err(invalid(line, column, TransmissionIssue::NotANumber));
+            }
+            if value.is_infinite() {
+                return Err(invalid(line, column, TransmissionIssue::Infinite(value)));
+            }
+            if k >= 3 && !(0. ..=1.).contains(&value) {
+                return Err(invalid(line, column, TransmissionIssue::OutOfRange(value)));
+            }
+            values[k] = value;
+        }
+
+        if let Some(previous) = records.last() {
+            let previous = [
+                previous.lambda_coll,
+                previous.lambda_22deg,
+                previous.lambda_3deg,
+            ];
+            for k in 0..3 {
+                if values[k] <= previous[k] {
+                    return Err(invalid(
+                        line,
+                        TRANSMISSION_COLUMNS[k].1,
+                        TransmissionIssue::NotIncreasing {
+                            previous: previous[k],
+                            value: values[k],
+                        },
+                    ));
+                }
+            }
+        }
+
+        records.push(AOIRecord {
+            lambda_coll: values[0],
+            lambda_22deg: values[1],
+            lambda_3deg: values[2],
+            t_coll: values[3],
+            t_22deg: values[4],
+            t_3deg: values[5],
+        });
+    }
+
+    if records.len() < 2 {
+        return Err(invalid(
+            None,
+            TRANSMISSION_COLUMNS[0].1,
+            TransmissionIssue::TooFewRows(records.len()),
+        ));
+    }
+    if common_range(&records).is_none() {
+        return Err(invalid(None, "lambda", TransmissionIssue::NoCommonRange));
+    }
+
+    Ok(records)
+}
+
+/// Range of wavelengths covered by all three wavelength columns.
+fn common_range(records: &[AOIRecord]) -> Option<(f64, f64)> {
+    let (first, last) = (records.first()?, records.last()?);
+    let lo = first
+        .lambda_coll
+        .max(first.lambda_22deg)
+        .max(first.lambda_3deg);
+    let hi = last
+        .lambda_coll
+        .min(last.lambda_22deg)
+        .min(last.lambda_3deg);
+    if lo < hi {
+        Some((lo, hi))
+    } else {
+        None
+    }
+}
+
+/// Transmission for every wavefront resampled onto a common wavelength grid: the collimated
+/// wavelengths within the range all the wavelength columns cover.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransmissionTable {
+    pub wavelength: Vec<f64>,
+    pub t_coll: Vec<f64>,
+    pub t_22deg: Vec<f64>,
+    pub t_3deg: Vec<f64>,
+}
+
+impl TransmissionTable {
+    /// Resample validated records (see `load_transmission_data`) onto a common grid. Fails if
+    /// the wavelength columns don't overlap.
+    pub fn resample(records: &[AOIRecord]) -> Result<Self> {
+        let (lo, hi) = common_range(records).ok_or_else(|| Error::InvalidTransmission {
+            path: "resampled records".to_owned(),
+            row: None,
+            column: "lambda".to_owned(),
+            issue: TransmissionIssue::NoCommonRange,
+        })?;
+        let wavelength = records
+            .iter()
+            .map(|r| r.lambda_coll)
+            .filter(|&w| w >= lo && w <= hi)
+            .collect::<Vec<_>>();
+
+        let column = |lambda: fn(&AOIRecord) -> f64, t: fn(&AOIRecord) -> f64| {
+            let x = records.iter().map(lambda).collect::<Vec<_>>();
+            let y = records.iter().map(t).collect::<Vec<_>>();
+            // Copy columns already on the grid so that they come through untouched.
+            if x.iter().zip(records).all(|(&w, r)| w == r.lambda_coll) {
+                let offset = x.iter().position(|&w| w >= lo).unwrap_or(0);
+                y[offset..offset + wavelength.len()].to_vec()
+            } else {
+                interp1d_linear(&x, &y, &wavelength, ExtrapolationMode::Fill(0., 0.)).to_vec()
+            }
+        };
+
+        Ok(TransmissionTable {
+            t_coll: column(|r| r.lambda_coll, |r| r.t_coll),
+            t_22deg: column(|r| r.lambda_22deg, |r| r.t_22deg),
+            t_3deg: column(|r| r.lambda_3deg, |r| r.t_3deg),
+            wavelength,
         })
-        .unzip()
+    }
+
+    /// Transmission for a wavefront, on `wavelength`.
+    pub fn column(&self, wavefront: Wavefront) -> &[f64] {
+        match wavefront {
+            Wavefront::TCOLL => &self.t_coll,
+            Wavefront::T3 => &self.t_3deg,
+            Wavefront::T22 => &self.t_22deg,
+        }
+    }
 }
 
-const FWHM: f64 = 2.3548200450309493;
+pub fn get_transmission(filter: Filter, wavefront: Wavefront) -> Result<(Vector, Vector)> {
+    Ok(filter.curve()?.transmission(wavefront))
+}
 
-pub fn get_tilt_shift(cwl: f64, tilts: &[f64]) -> Vector {
-    cwl * (1.
-        - ((RFR_IDX_RATIO) * (Vector::from(tilts) * std::f64::consts::PI / 180.).sin()).powi(2))
-    .sqrt()
-        - cwl
+/// Shift, in nm, of the filter's central wavelength at each of the given tilts (in degrees).
+pub fn get_tilt_shift(spec: &FilterSpec, tilts: &[f64]) -> Vector {
+    spec.cwl
+        * (1.
+            - ((1. / spec.effective_index)
+                * (Vector::from(tilts) * std::f64::consts::PI / 180.).sin())
+            .powi(2))
+        .sqrt()
+        - spec.cwl
 }
 
-pub fn get_laser_spectrum(laser_cwl: f64, laser_fwhm: f64) -> (Vector, Vector) {
-    let laser_std = laser_fwhm / FWHM;
-    let laser_lambda = arange(650., 670., 0.01);
-    let norm = Normal::new(laser_cwl, laser_std);
-    let laser_flux = laser_lambda
-        .iter()
-        .map(|&x| norm.pdf(x))
-        .collect::<Vector>();
-    (laser_lambda, laser_flux)
+/// Tilt, in degrees, that centres the filter on `wavelength`: the inverse of `get_tilt_shift`.
+/// Returns `None` if no tilt reaches it, i.e. if it lies redward of the CWL or beyond the
+/// blueward shift at 90 degrees.
+pub fn tilt_for_wavelength(spec: &FilterSpec, wavelength: f64) -> Option<f64> {
+    let ratio = wavelength / spec.cwl;
+    if ratio > 1. {
+        return None;
+    }
+    let sin = spec.effective_index * (1. - ratio * ratio).sqrt();
+    if sin > 1. {
+        return None;
+    }
+    Some(sin.asin() * 180. / std::f64::consts::PI)
+}
+
+/// Wavelengths and transmission of `filter` as described by `spec`, with its passband shifted by
+/// `filtershift` nm. The measured curve is first translated from the filter's nominal CWL to the
+/// one in `spec`, then stretched about it if the bandwidth in `spec` differs from the nominal one.
+pub fn shifted_transmission(
+    filter: Filter,
+    spec: &FilterSpec,
+    filtershift: Option<f64>,
+    wavefront: Wavefront,
+) -> Result<(Vector, Vector)> {
+    let curve = filter.curve()?;
+    let (mut t_wavelength, t_flux) = curve.transmission(wavefront);
+
+    t_wavelength += spec.cwl - curve.metadata.cwl;
+    if spec.bandwidth != curve.bandwidth() {
+        let stretch = spec.bandwidth / curve.bandwidth();
+        t_wavelength = t_wavelength
+            .iter()
+            .map(|&w| spec.cwl + (w - spec.cwl) * stretch)
+            .collect();
+    }
+    if let Some(shift) = filtershift {
+        t_wavelength += shift;
+    }
+
+    Ok((t_wavelength, t_flux))
 }
 
+/// Flux of a single Gaussian laser line transmitted by `filter`; `integrate_spectrum` for
+/// `SourceSpectrum::laser`.
 pub fn integrate_flux(
     filter: Filter,
+    spec: &FilterSpec,
     filtershift: Option<f64>,
     wavefront: Wavefront,
     laser_cwl: f64,
     laser_fwhm: f64,
-) -> f64 {
-    let (d_wavelength, d_flux) = get_laser_spectrum(laser_cwl, laser_fwhm);
-    let (mut t_wavelength, t_flux) = get_transmission(filter, wavefront);
+) -> Result<f64> {
+    integrate_spectrum(
+        filter,
+        spec,
+        filtershift,
+        wavefront,
+        &SourceSpectrum::laser(laser_cwl, laser_fwhm)?,
+    )
+}
 
-    if let Some(shift) = filtershift {
-        t_wavelength += shift;
+/// Hα and [NII] curves of the default `TransmissionModel` with the filter described by `spec`,
+/// on tilts from 0 to 20 degrees in steps of `stepsize`. The `MODEL_*` constants are its output.
+pub fn generate_model_transmission(
+    spec: &FilterSpec,
+    stepsize: f64,
+) -> Result<(Vector, Vector, Vector)> {
+    let curves = TransmissionModel::builder()
+        .spec(*spec)
+        .tilt_grid(0., 20., stepsize)
+        .cache_dir(None::<&str>)
+        .build()?;
+    Ok((
+        Vector::from(curves.tilt.as_slice()),
+        Vector::from(curves.flux[0].as_slice()),
+        Vector::from(curves.flux[1].as_slice()),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::{fs::File, io::Write};
+
+    #[test]
+    fn test_tilt_for_wavelength() {
+        let spec = FilterSpec::nominal(Filter::Bpf31Deg0).unwrap();
+        assert_eq!(spec, DEFAULT_FILTER_SPEC);
+
+        for &tilt in &[0., 5., 12.5] {
+            let shift = get_tilt_shift(&spec, &[tilt])[0];
+            let recovered = tilt_for_wavelength(&spec, spec.cwl + shift).unwrap();
+            assert!((recovered - tilt).abs() < 1e-6);
+        }
+        assert!(tilt_for_wavelength(&spec, spec.cwl + 1.).is_none());
+        assert!(tilt_for_wavelength(&spec, 500.).is_none());
     }
 
-    let itflux = interp1d_linear(
-        &t_wavelength,
-        &t_flux,
-        &d_wavelength,
-        ExtrapolationMode::Fill(0., 0.),
-    );
+    #[test]
+    fn test_unknown_filter() {
+        assert!(Filter::from_str("no-such-filter").is_err());
+        match Filter("no-such-filter").curve() {
+            Err(Error::UnknownFilter { name, .. }) => assert_eq!(name, "no-such-filter"),
+            other => panic!("{:?}", other.map(|c| &c.metadata.name)),
+        }
+    }
 
-    let (y, x): (Vector, Vector) = itflux
-        .iter()
-        .zip(d_flux)
-        .zip(d_wavelength)
-        .filter(|((&i_f, _), _)| i_f > 0.)
-        .map(|((&i_f, d_f), d_w)| (i_f * d_f, d_w))
-        .unzip();
-
-    trapezoid(&y, Some(&x), None)
-}
-
-pub fn generate_model_transmission(stepsize: f64) -> (Vector, Vector, Vector) {
-    let filter_cwl = 659.9;
-    let pnetilt = arange(0., 20., stepsize);
-    let pneshift = get_tilt_shift(filter_cwl, &pnetilt);
-
-    let pnecwl = 656.3;
-    let pnefluxout = pneshift
-        .par_iter()
-        .map(|&x| integrate_flux(Filter::Bpf31Deg0, Some(x), Wavefront::TCOLL, pnecwl, 0.61))
-        .collect::<Vector>();
-
-    let pnecwl_nii = 658.5;
-    let pnefluxout_nii = pneshift
-        .par_iter()
-        .map(|&x| {
-            integrate_flux(
-                Filter::Bpf31Deg0,
-                Some(x),
-                Wavefront::TCOLL,
-                pnecwl_nii,
-                0.61,
-            )
-        })
-        .collect::<Vector>();
+    #[test]
+    fn test_invalid_data() {
+        let dir = TempDir::new("invalid");
+        let header = "lambdacoll,Tcoll,lambda22deg,T22deg,lambda3deg,T3deg";
 
-    (pnetilt, pnefluxout, pnefluxout_nii)
+        for (rows, line, issue) in vec![
+            (
+                "655,0.1,655,0.1,655,0.1\n656,1.2,656,0.1,656,0.1",
+                Some(3),
+                TransmissionIssue::OutOfRange(1.2),
+            ),
+            (
+                "655,0.1,655,0.1,655,0.1\n656,NaN,656,0.1,656,0.1",
+                Some(3),
+                TransmissionIssue::NotANumber,
+            ),
+            (
+                "655,0.1,655,0.1,655,0.1\n654,0.1,656,0.1,656,0.1",
+                Some(3),
+                TransmissionIssue::NotIncreasing {
+                    previous: 655.,
+                    value: 654.,
+                },
+            ),
+            (
+                "655,0.1,655,0.1,655,0.1\n656,0.1,inf,0.1,656,0.1",
+                Some(3),
+                TransmissionIssue::Infinite(f64::INFINITY),
+            ),
+            (
+                "655,0.1,655,0.1,655,abc",
+                Some(2),
+                TransmissionIssue::Unparseable("abc".into()),
+            ),
+            (
+                "655,0.1,660,0.1,655,0.1\n656,0.1,661,0.1,656,0.1",
+                None,
+                TransmissionIssue::NoCommonRange,
+            ),
+        ] {
+            let path = dir.path().join("bad.csv");
+            File::create(&path)
+                .unwrap()
+                .write_all(format!("{}\n{}\n", header, rows).as_bytes())
+                .unwrap();
+            match load_transmission_data(&path) {
+                Err(Error::InvalidTransmission {
+                    row, issue: found, ..
+                }) => {
+                    assert_eq!(row, line, "{}", rows);
+                    assert_eq!(found, issue, "{}", rows);
+                }
+                other => panic!("{}: {:?}", rows, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_resample_per_column() {
+        let records = (0..5)
+            .map(|i| {
+                let w = 655. + i as f64;
+                AOIRecord {
+                    lambda_coll: w,
+                    lambda_22deg: w + 0.5,
+                    lambda_3deg: w,
+                    t_coll: 0.1 * i as f64,
+                    t_22deg: 0.1 * i as f64,
+                    t_3deg: 0.2,
+                }
+            })
+            .collect::<Vec<_>>();
+        let table = TransmissionTable::resample(&records).unwrap();
+
+        assert_eq!(table.wavelength, vec![656., 657., 658., 659.]);
+        assert_eq!(table.t_3deg, vec![0.2; 4]);
+        for (t, expected) in table.t_22deg.iter().zip(&[0.05, 0.15, 0.25, 0.35]) {
+            assert!((t - expected).abs() < 1e-12);
+        }
+
+        assert!(matches!(
+            TransmissionTable::resample(&records[..1]),
+            Err(Error::InvalidTransmission {
+                issue: TransmissionIssue::NoCommonRange,
+                ..
+            })
+        ));
+    }
 }
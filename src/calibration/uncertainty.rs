@@ -0,0 +1,245 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    fit::{fit_tilt_calibration, fit_weights, normalize_flux, tilt_residuals, TiltFit},
+    model::TransmissionCurves,
+};
+use crate::utils::{cmp_nan_last, quantile};
+
+/// Two-sided confidence level of the reported intervals.
+pub const CONFIDENCE_LEVEL: f64 = 0.95;
+/// Normal quantile corresponding to `CONFIDENCE_LEVEL`.
+const Z_CONFIDENCE: f64 = 1.959963984540054;
+/// Finite-difference steps in NII fraction and tilt shift (degrees) for the Jacobian.
+const JACOBIAN_STEPS: [f64; 2] = [1e-4, 1e-3];
+
+/// A fitted parameter with its standard error and confidence interval.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ParameterEstimate {
+    pub value: f64,
+    pub std_err: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// How the fit uncertainties were estimated.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum UncertaintyMethod {
+    /// Linearized covariance from the Jacobian of the residuals at the best fit.
+    Jacobian,
+    /// Refitting frames resampled with replacement this many times.
+    Bootstrap(usize),
+}
+
+/// Tilt calibration fit with uncertainties.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TiltFitUncertainty {
+    pub nii_fraction: ParameterEstimate,
+    pub tilt_shift: ParameterEstimate,
+    /// Correlation coefficient between NII fraction and tilt shift.
+    pub correlation: f64,
+    /// Weighted residual divided by the degrees of freedom.
+    pub reduced_chi2: f64,
+    pub method: UncertaintyMethod,
+}
+
+fn reduced_chi2(fit: &TiltFit, npoints: usize) -> f64 {
+    fit.residual / (npoints as f64 - 2.).max(1.)
+}
+
+/// Estimate uncertainties from the Jacobian of the weighted residuals at the best fit. The
+/// covariance is `s² (JᵀJ)⁻¹`, with the residual variance `s²` estimated by the reduced
+/// chi-square. Without flux uncertainties the measurements carry no independent errors; with
+/// them, the scaling absorbs any under- or overestimate of the uncertainties.
+pub fn jacobian_uncertainty(
+    curves: &TransmissionCurves,
+    datatilt: &[f64],
+    dataflux: &[f64],
+    dataerr: Option<&[f64]>,
+    fit: &TiltFit,
+) -> TiltFitUncertainty {
+    let datafluxnorm = normalize_flux(dataflux);
+    let weights = fit_weights(dataflux, dataerr);
+    let best = [fit.nii_fraction, fit.tilt_shift];
+    let residuals =
+        |p: &[f64]| tilt_residuals(curves, datatilt, &datafluxnorm, &weights, p[0], p[1]);
+
+    // Columns of the Jacobian, by central differences where the bounds on the NII fraction
+    // allow it.
+    let columns = (0..2)
+        .map(|k| {
+            let h = JACOBIAN_STEPS[k];
+            let (mut lo, mut hi) = (best, best);
+            lo[k] -= h;
+            hi[k] += h;
+            if k == 0 && lo[0] < 0. {
+                lo[0] = best[0];
+            }
+            if k == 0 && hi[0] > 1. {
+                hi[0] = best[0];
+            }
+            let width = hi[k] - lo[k];
+            residuals(&hi)
+                .iter()
+                .zip(residuals(&lo))
+                .map(|(h, l)| (h - l) / width)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+    let (a, b, d) = (
+        dot(&columns[0], &columns[0]),
+        dot(&columns[0], &columns[1]),
+        dot(&columns[1], &columns[1]),
+    );
+    let det = a * d - b * b;
+
+    let chi2 = reduced_chi2(fit, datatilt.len());
+    let (var_frac, var_shift, cov) = if det > 0. {
+        (chi2 * d / det, chi2 * a / det, -chi2 * b / det)
+    } else {
+        (f64::INFINITY, f64::INFINITY, f64::NAN)
+    };
+
+    let estimate = |value: f64, var: f64| {
+        let std_err = var.sqrt();
+        ParameterEstimate {
+            value,
+            std_err,
+            lower: value - Z_CONFIDENCE * std_err,
+            upper: value + Z_CONFIDENCE * std_err,
+        }
+    };
+
+    TiltFitUncertainty {
+        nii_fraction: estimate(fit.nii_fraction, var_frac),
+        tilt_shift: estimate(fit.tilt_shift, var_shift),
+        correlation: cov / (var_frac * var_shift).sqrt(),
+        reduced_chi2: chi2,
+        method: UncertaintyMethod::Jacobian,
+    }
+}
+
+/// Estimate uncertainties by refitting `nboot` bootstrap resamples of the frames. Intervals are
+/// the percentiles of the refitted parameters. Resamples that cannot be fitted, e.g. because they
+/// miss every frame with flux, are dropped. Without any frames there is nothing to resample, and
+/// the standard errors and intervals are NaN.
+pub fn bootstrap_uncertainty(
+    curves: &TransmissionCurves,
+    datatilt: &[f64],
+    dataflux: &[f64],
+    dataerr: Option<&[f64]>,
+    fit: &TiltFit,
+    nboot: usize,
+) -> TiltFitUncertainty {
+    let n = datatilt.len();
+    if n == 0 {
+        let undetermined = |value: f64| ParameterEstimate {
+            value,
+            std_err: f64::NAN,
+            lower: f64::NAN,
+            upper: f64::NAN,
+        };
+        return TiltFitUncertainty {
+            nii_fraction: undetermined(fit.nii_fraction),
+            tilt_shift: undetermined(fit.tilt_shift),
+            correlation: f64::NAN,
+            reduced_chi2: reduced_chi2(fit, n),
+            method: UncertaintyMethod::Bootstrap(nboot),
+        };
+    }
+
+    let refits = (0..nboot)
+        .into_par_iter()
+        .filter_map(|_| {
+            let indices = (0..n).map(|_| alea::u32() as usize % n).collect::<Vec<_>>();
+            let pick = |x: &[f64]| indices.iter().map(|&i| x[i]).collect::<Vec<_>>();
+            let err = dataerr.map(pick);
+            fit_tilt_calibration(curves, &pick(datatilt), &pick(dataflux), err.as_deref())
+                .ok()
+                .map(|refit| (refit.nii_fraction, refit.tilt_shift))
+        })
+        .collect::<Vec<_>>();
+    let nrefits = refits.len();
+
+    let (fracs, shifts): (Vec<f64>, Vec<f64>) = refits.into_iter().unzip();
+
+    let mean = |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64;
+    let (mean_frac, mean_shift) = (mean(&fracs), mean(&shifts));
+    let var = |x: &[f64], m: f64| {
+        x.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (x.len() as f64 - 1.).max(1.)
+    };
+    let (var_frac, var_shift) = (var(&fracs, mean_frac), var(&shifts, mean_shift));
+    let cov = fracs
+        .iter()
+        .zip(&shifts)
+        .map(|(f, s)| (f - mean_frac) * (s - mean_shift))
+        .sum::<f64>()
+        / (nrefits as f64 - 1.).max(1.);
+
+    let tail = (1. - CONFIDENCE_LEVEL) / 2.;
+    let estimate = |value: f64, samples: &[f64], var: f64| {
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(cmp_nan_last);
+        ParameterEstimate {
+            value,
+            std_err: var.sqrt(),
+            lower: quantile(&sorted, tail),
+            upper: quantile(&sorted, 1. - tail),
+        }
+    };
+
+    TiltFitUncertainty {
+        nii_fraction: estimate(fit.nii_fraction, &fracs, var_frac),
+        tilt_shift: estimate(fit.tilt_shift, &shifts, var_shift),
+        correlation: cov / (var_frac * var_shift).sqrt(),
+        reduced_chi2: reduced_chi2(fit, n),
+        method: UncertaintyMethod::Bootstrap(nboot),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::calibration::simulation::simulated_sweep;
+
+    #[test]
+    fn test_intervals_cover_injected_offset() {
+        // With this noise realization the fit lands within 0.02 degrees of the injected offset,
+        // well inside the Jacobian interval, whose half-width is over a degree.
+        let (datatilt, dataflux) = simulated_sweep(0.02);
+
+        let curves = TransmissionCurves::default();
+        let fit = fit_tilt_calibration(&curves, &datatilt, &dataflux, None).unwrap();
+        assert!((fit.tilt_shift - 2.).abs() < 0.05, "{:?}", fit);
+
+        let jacobian = jacobian_uncertainty(&curves, &datatilt, &dataflux, None, &fit);
+        assert!(jacobian.tilt_shift.std_err > 0.);
+        assert!(jacobian.tilt_shift.lower < 2. && 2. < jacobian.tilt_shift.upper);
+        assert!(jacobian.nii_fraction.lower < 0.4 && 0.4 < jacobian.nii_fraction.upper);
+        assert!(jacobian.reduced_chi2 > 0.);
+
+        // The resampling is still random, but the refits scatter on both sides of the best fit,
+        // so the percentile interval contains it for all but vanishingly unlikely draws.
+        let bootstrap = bootstrap_uncertainty(&curves, &datatilt, &dataflux, None, &fit, 30);
+        assert!((bootstrap.tilt_shift.value - fit.tilt_shift).abs() < 1e-12);
+        assert!(bootstrap.tilt_shift.lower <= fit.tilt_shift);
+        assert!(fit.tilt_shift <= bootstrap.tilt_shift.upper);
+    }
+
+    #[test]
+    fn test_bootstrap_without_frames() {
+        let fit = TiltFit {
+            nii_fraction: 0.4,
+            tilt_shift: 2.,
+            residual: 0.,
+        };
+        let bootstrap =
+            bootstrap_uncertainty(&TransmissionCurves::default(), &[], &[], None, &fit, 10);
+        assert_eq!(bootstrap.tilt_shift.value, 2.);
+        assert!(bootstrap.tilt_shift.std_err.is_nan());
+        assert!(bootstrap.nii_fraction.lower.is_nan());
+    }
+}
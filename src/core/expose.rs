@@ -1,4 +1,6 @@
-use std::process;
+use std::process::{self, Child, Stdio};
+
+use crate::error::{Error, Result};
 
 pub enum ImageType {
     Light,
@@ -6,27 +8,64 @@ pub enum ImageType {
 }
 
 // TODO: make this async!
-pub fn expose(imagetype: ImageType, duration: f64, savepath: &str) {
-    let cmd = match imagetype {
-        ImageType::Light => process::Command::new("dfcore")
-            .args(&[
-                "expose",
-                "--duration",
-                &duration.to_string(),
-                "--file",
-                savepath,
-            ])
-            .spawn(),
-        ImageType::Dark => process::Command::new("dfcore")
-            .args(&[
-                "expose",
-                "--dark",
-                "--duration",
-                &duration.to_string(),
-                "--file",
-                savepath,
-            ])
-            .spawn(),
+/// Start an exposure with `dfcore` without waiting for it to finish. Fails if `dfcore` cannot be
+/// started; pass the returned process to `wait_for` to know whether the exposure succeeded.
+pub fn expose(imagetype: ImageType, duration: f64, savepath: &str) -> Result<Child> {
+    let duration = duration.to_string();
+    let mut args = vec!["expose"];
+    if let ImageType::Dark = imagetype {
+        args.push("--dark");
+    }
+    args.extend(&["--duration", &duration, "--file", savepath]);
+    spawn("dfcore", &args)
+}
+
+/// Wait for a process started by `expose`, turning a non-zero exit into a `Subprocess` error.
+pub fn wait_for(child: Child) -> Result<()> {
+    let output = child
+        .wait_with_output()
+        .map_err(|e| Error::subprocess("dfcore", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::subprocess(
+            "dfcore",
+            String::from_utf8_lossy(&output.stderr).trim(),
+        ))
+    }
+}
+
+/// Start `program`, turning a failure to start it into a `Subprocess` error.
+fn spawn(program: &str, args: &[&str]) -> Result<Child> {
+    process::Command::new(program)
+        .args(args)
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::subprocess(program, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_missing_program() {
+        match spawn("dragonfly-no-such-program", &[]) {
+            Err(Error::Subprocess { program, .. }) => {
+                assert_eq!(program, "dragonfly-no-such-program")
+            }
+            other => panic!("{:?}", other.map(|child| child.id())),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_zero_exit() {
+        assert!(wait_for(spawn("true", &[]).unwrap()).is_ok());
+        let child = spawn("sh", &["-c", "echo out of disk >&2; exit 3"]).unwrap();
+        match wait_for(child) {
+            Err(Error::Subprocess { message, .. }) => assert_eq!(message, "out of disk"),
+            other => panic!("{:?}", other),
+        }
     }
-    .expect("Could not spawn dfcore::expose!");
 }
@@ -0,0 +1,229 @@
+use std::{fmt, io};
+
+/// Errors from the hardware, calibration and analysis layers.
+#[derive(Debug)]
+pub enum Error {
+    /// The serial port could not be opened or configured.
+    Serial(serialport::Error),
+    /// Reading or writing a file, pipe or serial port failed.
+    Io(io::Error),
+    /// The filter-tilter sent a reply we could not make sense of.
+    Protocol(String),
+    /// A filter-tilter command was refused because it would violate the travel limits.
+    Interlock(String),
+    /// Travel limits whose minimum is not below their maximum.
+    InvalidLimits { min: f64, max: f64 },
+    /// The filter-tilter did not settle at the requested angle in time.
+    /// `stop_error` says why the emergency stop issued after the timeout failed, if it did.
+    NotSettled {
+        target: f64,
+        reported: f64,
+        stop_error: Option<String>,
+    },
+    /// The simulated filter-tilter state file could not be read or written.
+    Simulation(String),
+    /// An external program (e.g. `dfcore` or `sex`) could not be run or exited unsuccessfully.
+    Subprocess { program: String, message: String },
+    /// SExtractor output could not be parsed into a catalog.
+    Catalog(String),
+    /// Reading or writing a FITS file failed.
+    Fits(fitsio::errors::Error),
+    /// Reading or writing a CSV file failed.
+    Csv(csv::Error),
+    /// Serializing or deserializing JSON failed.
+    Json(serde_json::Error),
+    /// A source spectrum component is invalid, e.g. a line without a positive width.
+    Spectrum(String),
+    /// A transmission model cannot be computed as configured, e.g. without any laser lines.
+    Model(String),
+    /// The calibration data cannot be fitted, e.g. because no spot flux was measured.
+    Fit(String),
+    /// No filter tilt within the travel limits reaches the requested wavelength.
+    Unreachable(String),
+    /// No filter of this name was found in the registry directory.
+    UnknownFilter { name: String, dir: String },
+    /// A calibration run journal could not be parsed.
+    Journal(String),
+    /// A master dark could not be made or applied, e.g. frames of different sizes.
+    Dark(String),
+    /// A light frame to be dark-subtracted does not record the sensor temperature.
+    MissingTemperature(String),
+    /// A tilt scan file could not be parsed.
+    Scan(String),
+    /// A filter transmission CSV failed validation. `row` is the line number in the file (the
+    /// header is line 1), if the problem is with a particular line.
+    InvalidTransmission {
+        path: String,
+        row: Option<usize>,
+        column: String,
+        issue: TransmissionIssue,
+    },
+}
+
+/// What is wrong with a value or column in a filter transmission CSV.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransmissionIssue {
+    /// The column is not in the header.
+    MissingColumn,
+    /// The field is not a number.
+    Unparseable(String),
+    /// The field is NaN.
+    NotANumber,
+    /// The field is infinite.
+    Infinite(f64),
+    /// A transmission outside [0, 1].
+    OutOfRange(f64),
+    /// A wavelength not greater than the one on the previous line.
+    NotIncreasing { previous: f64, value: f64 },
+    /// Fewer than two rows of data.
+    TooFewRows(usize),
+    /// The wavelength columns do not overlap.
+    NoCommonRange,
+}
+
+impl fmt::Display for TransmissionIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransmissionIssue::MissingColumn => write!(f, "missing column"),
+            TransmissionIssue::Unparseable(field) => write!(f, "could not parse {:?}", field),
+            TransmissionIssue::NotANumber => write!(f, "value is NaN"),
+            TransmissionIssue::Infinite(value) => write!(f, "value {} is not finite", value),
+            TransmissionIssue::OutOfRange(value) => {
+                write!(f, "transmission {} is outside [0, 1]", value)
+            }
+            TransmissionIssue::NotIncreasing { previous, value } => write!(
+                f,
+                "wavelength {} does not increase from the previous {}",
+                value, previous
+            ),
+            TransmissionIssue::TooFewRows(n) => write!(f, "only {} rows of data", n),
+            TransmissionIssue::NoCommonRange => {
+                write!(f, "wavelength columns have no range in common")
+            }
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Shorthand for a `Subprocess` error.
+    pub fn subprocess(program: &str, message: impl fmt::Display) -> Self {
+        Error::Subprocess {
+            program: program.to_owned(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Serial(e) => write!(f, "Serial port error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Protocol(msg) => write!(f, "Filter-tilter protocol error: {}", msg),
+            Error::Interlock(msg) => write!(f, "Refused by interlock: {}", msg),
+            Error::InvalidLimits { min, max } => write!(
+                f,
+                "Invalid travel limits [{}, {}]: the minimum must be below the maximum",
+                min, max
+            ),
+            Error::NotSettled {
+                target,
+                reported,
+                stop_error,
+            } => {
+                write!(
+                    f,
+                    "Filter-tilter did not settle at {} (last reported {})",
+                    target, reported
+                )?;
+                match stop_error {
+                    Some(e) => write!(f, "; the emergency stop also failed: {}", e),
+                    None => Ok(()),
+                }
+            }
+            Error::Simulation(msg) => write!(f, "Simulation error: {}", msg),
+            Error::Subprocess { program, message } => write!(f, "{} failed: {}", program, message),
+            Error::Catalog(msg) => write!(f, "Could not parse catalog: {}", msg),
+            Error::Fits(e) => write!(f, "FITS error: {}", e),
+            Error::Csv(e) => write!(f, "CSV error: {}", e),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+            Error::Spectrum(msg) => write!(f, "Invalid source spectrum: {}", msg),
+            Error::Model(msg) => write!(f, "Invalid transmission model: {}", msg),
+            Error::Fit(msg) => write!(f, "Cannot fit the calibration: {}", msg),
+            Error::Unreachable(msg) => write!(f, "Unreachable wavelength: {}", msg),
+            Error::UnknownFilter { name, dir } => {
+                write!(f, "Filter {} is not in the registry at {}", name, dir)
+            }
+            Error::Journal(msg) => write!(f, "Could not read run journal: {}", msg),
+            Error::Dark(msg) => write!(f, "Dark subtraction failed: {}", msg),
+            Error::MissingTemperature(path) => write!(
+                f,
+                "{} does not record the sensor temperature, which dark subtraction needs",
+                path
+            ),
+            Error::Scan(msg) => write!(f, "Could not parse tilt scan: {}", msg),
+            Error::InvalidTransmission {
+                path,
+                row,
+                column,
+                issue,
+            } => match row {
+                Some(row) => write!(
+                    f,
+                    "Invalid transmission data in {} line {}, column {}: {}",
+                    path, row, column, issue
+                ),
+                None => write!(
+                    f,
+                    "Invalid transmission data in {}, column {}: {}",
+                    path, column, issue
+                ),
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Serial(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Fits(e) => Some(e),
+            Error::Csv(e) => Some(e),
+            Error::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::Serial(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<fitsio::errors::Error> for Error {
+    fn from(e: fitsio::errors::Error) -> Self {
+        Error::Fits(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Csv(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
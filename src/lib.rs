@@ -1,5 +1,8 @@
 pub mod calibration;
 pub mod core;
+pub mod error;
 pub mod focuser;
+pub mod optimize;
+pub mod sampling;
 pub mod sextractor;
 pub mod utils;
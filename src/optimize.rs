@@ -0,0 +1,139 @@
+/// Outcome of a minimization.
+#[derive(Debug, Clone)]
+pub struct Minimum {
+    /// Location of the minimum.
+    pub x: Vec<f64>,
+    /// Value of the objective at the minimum.
+    pub fx: f64,
+    /// Number of iterations taken.
+    pub iterations: usize,
+}
+
+/// Minimize `f` with the Nelder-Mead simplex method, starting from `x0`. The initial simplex is
+/// built by stepping `step[i]` along each axis. Stops once the spread of objective values and
+/// the size of the simplex both fall below `tol`, or after `max_iter` iterations. The objective
+/// may return infinity to mark a point as infeasible.
+pub fn nelder_mead<F: Fn(&[f64]) -> f64>(
+    f: F,
+    x0: &[f64],
+    step: &[f64],
+    tol: f64,
+    max_iter: usize,
+) -> Minimum {
+    assert_eq!(
+        x0.len(),
+        step.len(),
+        "x0 and step must have the same length."
+    );
+    let n = x0.len();
+
+    let (alpha, gamma, rho, sigma) = (1., 2., 0.5, 0.5);
+
+    let mut simplex = (0..=n)
+        .map(|i| {
+            let mut x = x0.to_vec();
+            if i > 0 {
+                x[i - 1] += step[i - 1];
+            }
+            let fx = f(&x);
+            (x, fx)
+        })
+        .collect::<Vec<_>>();
+
+    let mut iterations = 0;
+    while iterations < max_iter {
+        simplex.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        let (best, worst) = (simplex[0].1, simplex[n].1);
+        let size = simplex[1..]
+            .iter()
+            .map(|(x, _)| {
+                x.iter()
+                    .zip(&simplex[0].0)
+                    .map(|(a, b)| (a - b).abs())
+                    .fold(0., f64::max)
+            })
+            .fold(0., f64::max);
+        if (worst - best).abs() <= tol && size <= tol {
+            break;
+        }
+        iterations += 1;
+
+        let centroid = (0..n)
+            .map(|j| simplex[..n].iter().map(|(x, _)| x[j]).sum::<f64>() / n as f64)
+            .collect::<Vec<_>>();
+        let towards = |coef: f64| {
+            centroid
+                .iter()
+                .zip(&simplex[n].0)
+                .map(|(c, w)| c + coef * (w - c))
+                .collect::<Vec<_>>()
+        };
+
+        let reflected = towards(-alpha);
+        let f_reflected = f(&reflected);
+
+        if f_reflected < simplex[0].1 {
+            let expanded = towards(-gamma);
+            let f_expanded = f(&expanded);
+            simplex[n] = if f_expanded < f_reflected {
+                (expanded, f_expanded)
+            } else {
+                (reflected, f_reflected)
+            };
+        } else if f_reflected < simplex[n - 1].1 {
+            simplex[n] = (reflected, f_reflected);
+        } else {
+            let contracted = if f_reflected < simplex[n].1 {
+                towards(-rho)
+            } else {
+                towards(rho)
+            };
+            let f_contracted = f(&contracted);
+
+            if f_contracted < f_reflected.min(simplex[n].1) {
+                simplex[n] = (contracted, f_contracted);
+            } else {
+                let x_best = simplex[0].0.clone();
+                for (x, fx) in simplex[1..].iter_mut() {
+                    for (xi, bi) in x.iter_mut().zip(&x_best) {
+                        *xi = bi + sigma * (*xi - bi);
+                    }
+                    *fx = f(&x[..]);
+                }
+            }
+        }
+    }
+
+    simplex.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    let (x, fx) = simplex.swap_remove(0);
+
+    Minimum { x, fx, iterations }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nelder_mead_rosenbrock() {
+        let rosenbrock = |x: &[f64]| (1. - x[0]).powi(2) + 100. * (x[1] - x[0].powi(2)).powi(2);
+        let min = nelder_mead(rosenbrock, &[-1.2, 1.], &[0.5, 0.5], 1e-10, 10_000);
+        assert!((min.x[0] - 1.).abs() < 1e-4);
+        assert!((min.x[1] - 1.).abs() < 1e-4);
+        assert!(min.fx < 1e-8);
+    }
+
+    #[test]
+    fn test_nelder_mead_infeasible_region() {
+        let f = |x: &[f64]| {
+            if x[0] < 2. {
+                f64::INFINITY
+            } else {
+                (x[0] - 1.).powi(2)
+            }
+        };
+        let min = nelder_mead(f, &[5.], &[1.], 1e-10, 1000);
+        assert!((min.x[0] - 2.).abs() < 1e-4);
+    }
+}
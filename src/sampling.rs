@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{cmp_nan_last, quantile};
+
+/// Samples drawn by an `EnsembleSampler`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chain {
+    /// Positions of every walker at every step, indexed as `[step][walker][parameter]`.
+    pub samples: Vec<Vec<Vec<f64>>>,
+    /// Log-probability of every walker at every step, indexed as `[step][walker]`.
+    pub log_prob: Vec<Vec<f64>>,
+    /// Fraction of proposed moves that were accepted, per walker.
+    pub acceptance_fraction: Vec<f64>,
+}
+
+/// Summary of the marginal posterior of one parameter.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ParameterSummary {
+    pub mean: f64,
+    pub std: f64,
+    pub median: f64,
+    /// 16th percentile, i.e. the lower edge of the central 68% interval.
+    pub p16: f64,
+    /// 84th percentile, i.e. the upper edge of the central 68% interval.
+    pub p84: f64,
+}
+
+impl Chain {
+    /// All samples after discarding the first `burn` steps and keeping every `thin`th step
+    /// after that, flattened over walkers.
+    pub fn flat_samples(&self, burn: usize, thin: usize) -> Vec<Vec<f64>> {
+        self.samples
+            .iter()
+            .skip(burn)
+            .step_by(thin.max(1))
+            .flat_map(|walkers| walkers.iter().cloned())
+            .collect()
+    }
+
+    /// Marginal summary of each parameter, over the same samples as `flat_samples`.
+    pub fn summary(&self, burn: usize, thin: usize) -> Vec<ParameterSummary> {
+        let flat = self.flat_samples(burn, thin);
+        let ndim = flat.first().map(|s| s.len()).unwrap_or(0);
+
+        (0..ndim)
+            .map(|k| {
+                let mut x = flat.iter().map(|s| s[k]).collect::<Vec<_>>();
+                x.sort_by(cmp_nan_last);
+                let n = x.len() as f64;
+                let mean = x.iter().sum::<f64>() / n;
+                let var = x.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.).max(1.);
+                ParameterSummary {
+                    mean,
+                    std: var.sqrt(),
+                    median: quantile(&x, 0.5),
+                    p16: quantile(&x, 0.16),
+                    p84: quantile(&x, 0.84),
+                }
+            })
+            .collect()
+    }
+
+    /// Mean acceptance fraction over all walkers.
+    pub fn mean_acceptance_fraction(&self) -> f64 {
+        self.acceptance_fraction.iter().sum::<f64>() / self.acceptance_fraction.len() as f64
+    }
+}
+
+/// Affine-invariant ensemble sampler using the stretch move of Goodman & Weare (2010), as in
+/// `emcee`. Each walker is moved along the line joining it to another, randomly chosen walker.
+#[derive(Debug, Clone, Copy)]
+pub struct EnsembleSampler {
+    /// Scale of the stretch move. 2 works well for most problems.
+    pub a: f64,
+}
+
+impl Default for EnsembleSampler {
+    fn default() -> Self {
+        EnsembleSampler { a: 2. }
+    }
+}
+
+impl EnsembleSampler {
+    /// Run the sampler for `nsteps`, starting the walkers at `initial` (one position per walker;
+    /// at least two walkers are needed, and usually at least twice as many as parameters).
+    /// `log_prob` should return negative infinity outside the support of the posterior.
+    pub fn run<F: Fn(&[f64]) -> f64>(
+        &self,
+        log_prob: F,
+        initial: &[Vec<f64>],
+        nsteps: usize,
+    ) -> Chain {
+        let nwalkers = initial.len();
+        assert!(nwalkers >= 2, "The ensemble needs at least two walkers.");
+        let ndim = initial[0].len();
+
+        let mut positions = initial.to_vec();
+        let mut lp = positions.iter().map(|p| log_prob(p)).collect::<Vec<_>>();
+        let mut naccepted = vec![0usize; nwalkers];
+
+        let mut samples = Vec::with_capacity(nsteps);
+        let mut log_probs = Vec::with_capacity(nsteps);
+
+        for _ in 0..nsteps {
+            for k in 0..nwalkers {
+                // Pick a different walker to stretch towards.
+                let mut j = alea::u32() as usize % (nwalkers - 1);
+                if j >= k {
+                    j += 1;
+                }
+
+                let z = ((self.a - 1.) * alea::f64() + 1.).powi(2) / self.a;
+                let proposal = positions[j]
+                    .iter()
+                    .zip(&positions[k])
+                    .map(|(xj, xk)| xj + z * (xk - xj))
+                    .collect::<Vec<_>>();
+                let lp_proposal = log_prob(&proposal);
+
+                let log_accept = (ndim as f64 - 1.) * z.ln() + lp_proposal - lp[k];
+                if lp_proposal.is_finite() && alea::f64().ln() < log_accept {
+                    positions[k] = proposal;
+                    lp[k] = lp_proposal;
+                    naccepted[k] += 1;
+                }
+            }
+            samples.push(positions.clone());
+            log_probs.push(lp.clone());
+        }
+
+        Chain {
+            samples,
+            log_prob: log_probs,
+            acceptance_fraction: naccepted
+                .iter()
+                .map(|&n| n as f64 / nsteps.max(1) as f64)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::randn;
+
+    #[test]
+    fn test_samples_gaussian() {
+        let log_prob = |x: &[f64]| -0.5 * ((x[0] - 3.) / 2.).powi(2) - 0.5 * (x[1] / 0.5).powi(2);
+        let initial = (0..16)
+            .map(|_| vec![3. + 0.1 * randn(), 0.1 * randn()])
+            .collect::<Vec<_>>();
+
+        let chain = EnsembleSampler::default().run(log_prob, &initial, 2000);
+        let summary = chain.summary(500, 1);
+
+        assert!((summary[0].mean - 3.).abs() < 0.3);
+        assert!((summary[0].std - 2.).abs() < 0.3);
+        assert!(summary[1].mean.abs() < 0.1);
+        assert!((summary[1].std - 0.5).abs() < 0.1);
+        assert!(chain.mean_acceptance_fraction() > 0.2);
+    }
+}
@@ -1,7 +1,8 @@
-use arrayvec::ArrayVec;
 use lexical::parse;
 use serde::{Deserialize, Serialize};
 
+use crate::error::{Error, Result};
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct CatalogObject {
     //   outputs selected in dragonfly.param file
@@ -20,11 +21,11 @@ pub struct CatalogObject {
     //  13 ELONGATION             A_IMAGE/B_IMAGE
     //  14 BACKGROUND             Background at centroid position                            [count]
     #[serde(skip_serializing, alias = "Number")]
-    number: usize,
+    pub number: usize,
     #[serde(skip_serializing, alias = "XImage")]
-    x_image: f64,
+    pub x_image: f64,
     #[serde(skip_serializing, alias = "YImage")]
-    y_image: f64,
+    pub y_image: f64,
     #[serde(skip_serializing, alias = "XMinImage")]
     x_min_image: usize,
     #[serde(skip_serializing, alias = "YMinImage")]
@@ -49,61 +50,113 @@ pub struct CatalogObject {
     background: f64,
 }
 
-pub fn run_sextractor(filepath: &str) -> Result<Vec<CatalogObject>, String> {
-    match std::fs::metadata(filepath) {
-        Ok(_) => {
-            let proc = std::process::Command::new("sex")
-                .args(&[
-                    filepath,
-                    "-c",
-                    "/home/js/programs/dragonfly/src/sextractor/dragonfly.sex",
-                ])
-                .output()
-                .expect("Could not run SExtractor on file.");
-            if proc.status.success() {
-                let output_str = String::from_utf8_lossy(&proc.stdout);
-                Ok(deserialize_sextractor(&output_str))
-            } else {
-                Err(String::from_utf8_lossy(&proc.stderr).to_string())
-            }
-        }
-        Err(_) => panic!("File does not exist at {}.", filepath),
+pub fn run_sextractor(filepath: &str) -> Result<Vec<CatalogObject>> {
+    std::fs::metadata(filepath)?;
+
+    let proc = std::process::Command::new("sex")
+        .args(&[
+            filepath,
+            "-c",
+            "/home/js/programs/dragonfly/src/sextractor/dragonfly.sex",
+        ])
+        .output()
+        .map_err(|e| Error::subprocess("sex", e))?;
+
+    if proc.status.success() {
+        let output_str = String::from_utf8_lossy(&proc.stdout);
+        deserialize_sextractor(&output_str)
+    } else {
+        Err(Error::subprocess(
+            "sex",
+            String::from_utf8_lossy(&proc.stderr).trim(),
+        ))
     }
 }
 
-pub fn deserialize_sextractor(output: &str) -> Vec<CatalogObject> {
+fn parse_field<T: lexical::FromLexical>(fields: &[&str], idx: usize, name: &str) -> Result<T> {
+    fields
+        .get(idx)
+        .and_then(|f| parse::<T, _>(f).ok())
+        .ok_or_else(|| Error::Catalog(format!("failed to parse {} in column {}", name, idx + 1)))
+}
+
+pub fn deserialize_sextractor(output: &str) -> Result<Vec<CatalogObject>> {
     // takes in output from `run_sextractor`
 
     if output.is_empty() {
-        return vec![];
+        return Ok(vec![]);
     }
 
     output
         .split("\n")
         .filter(|x| !x.is_empty())
         .map(|line| {
-            let av = line
-                .trim()
-                .split_whitespace()
-                .collect::<ArrayVec<&str, 14>>();
-            CatalogObject {
-                number: parse::<usize, _>(av[0]).expect("Failed to parse number"),
-                x_image: parse::<f64, _>(av[1]).expect("Failed to parse x_image."),
-                y_image: parse::<f64, _>(av[2]).expect("Failed to parse y_image."),
-                x_min_image: parse::<usize, _>(av[3]).expect("Failed to parse x_min_image."),
-                y_min_image: parse::<usize, _>(av[4]).expect("Failed to parse y_min_image."),
-                x_max_image: parse::<usize, _>(av[5]).expect("Failed to parse x_max_image."),
-                y_max_image: parse::<usize, _>(av[6]).expect("Failed to parse y_max_image."),
-                flux: parse::<f64, _>(av[7]).expect("Failed to parse flux."),
-                flags: parse::<usize, _>(av[8]).expect("Failed to parse flags."),
-                fwhm: parse::<f64, _>(av[9]).expect("Failed to parse fwhm."),
-                mag_best: parse::<f64, _>(av[10]).expect("Failed to parse mag_best."),
-                area: parse::<f64, _>(av[11]).expect("Failed to parse area."),
-                axial_ratio: 1.
-                    / parse::<f64, _>(av[12])
-                        .expect("Failed to parse axial_ratio (1. / elongation)."),
-                background: parse::<f64, _>(av[13]).expect("Failed to parse background."),
+            let av = line.trim().split_whitespace().collect::<Vec<&str>>();
+            if av.len() != 14 {
+                return Err(Error::Catalog(format!(
+                    "expected 14 columns, found {} in {:?}",
+                    av.len(),
+                    line
+                )));
             }
+            Ok(CatalogObject {
+                number: parse_field(&av, 0, "number")?,
+                x_image: parse_field(&av, 1, "x_image")?,
+                y_image: parse_field(&av, 2, "y_image")?,
+                x_min_image: parse_field(&av, 3, "x_min_image")?,
+                y_min_image: parse_field(&av, 4, "y_min_image")?,
+                x_max_image: parse_field(&av, 5, "x_max_image")?,
+                y_max_image: parse_field(&av, 6, "y_max_image")?,
+                flux: parse_field(&av, 7, "flux")?,
+                flags: parse_field(&av, 8, "flags")?,
+                fwhm: parse_field(&av, 9, "fwhm")?,
+                mag_best: parse_field(&av, 10, "mag_best")?,
+                area: parse_field(&av, 11, "area")?,
+                axial_ratio: 1. / parse_field::<f64>(&av, 12, "axial_ratio (1. / elongation)")?,
+                background: parse_field(&av, 13, "background")?,
+            })
         })
-        .collect::<Vec<_>>()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LINE: &str = "1 500.2 499.8 490 510 489 511 123456.7 0 3.1 -12.7 96 1.25 100.4";
+
+    #[test]
+    fn test_deserialize() {
+        let catalog = deserialize_sextractor(&format!("{}\n{}\n", LINE, LINE)).unwrap();
+        assert_eq!(catalog.len(), 2);
+        assert_eq!((catalog[0].number, catalog[0].area), (1, 96.));
+        assert_eq!(catalog[0].flux, 123456.7);
+        assert_eq!(catalog[0].axial_ratio, 0.8);
+        assert!(deserialize_sextractor("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_wrong_column_count() {
+        match deserialize_sextractor("1 500.2 499.8") {
+            Err(Error::Catalog(msg)) => assert!(msg.contains("found 3"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unparseable_field() {
+        let line = LINE.replace("123456.7", "nan?");
+        match deserialize_sextractor(&line) {
+            Err(Error::Catalog(msg)) => assert!(msg.contains("flux in column 8"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_missing_file() {
+        match run_sextractor("/nonexistent/dragonfly-frame.fits") {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+            other => panic!("{:?}", other),
+        }
+    }
 }
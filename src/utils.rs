@@ -1,9 +1,90 @@
+use std::cmp::Ordering;
+
 /// Round a float to a given number of digits.
 pub fn round_to_digits(x: f64, n: usize) -> f64 {
     let t = 10_f64.powi(n as i32);
     (x * t).round() / t
 }
 
+/// Quantile `q` in [0, 1] of already sorted data, interpolating linearly between points.
+pub fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let pos = q.max(0.).min(1.) * (sorted.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[lo] + (pos - lo as f64) * (sorted[hi] - sorted[lo])
+}
+
+/// Total order on floats for sorting, with NaN after everything else (including infinity), so
+/// that a NaN objective or score never comes first and never panics.
+pub fn cmp_nan_last(a: &f64, b: &f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (false, false) => a.partial_cmp(b).unwrap(),
+        (a_nan, b_nan) => a_nan.cmp(&b_nan),
+    }
+}
+
+/// Draw a sample from the standard normal distribution (Box-Muller).
+pub fn randn() -> f64 {
+    let u1 = 1. - alea::f64();
+    let u2 = alea::f64();
+    (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+}
+
+/// Draw a sample from a Poisson distribution with the given mean. Large means use the normal
+/// approximation.
+pub fn poisson(lambda: f64) -> f64 {
+    if lambda <= 0. {
+        return 0.;
+    }
+    if lambda > 30. {
+        return (lambda + lambda.sqrt() * randn()).round().max(0.);
+    }
+
+    // Knuth's algorithm.
+    let limit = (-lambda).exp();
+    let mut k = 0.;
+    let mut p = alea::f64();
+    while p > limit {
+        k += 1.;
+        p *= alea::f64();
+    }
+    k
+}
+
+/// A fresh directory under the system temporary directory for tests, removed with everything in
+/// it when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new(prefix: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("dragonfly-{}-{}", prefix, alea::u32()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempDir {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -23,4 +104,22 @@ mod test {
         assert_eq!(round_to_digits(c, 1), 1.2);
         assert_eq!(round_to_digits(c, 2), 1.23);
     }
+
+    #[test]
+    fn test_quantile() {
+        let x = [1., 2., 3., 4., 5.];
+        assert_eq!(quantile(&x, 0.), 1.);
+        assert_eq!(quantile(&x, 0.5), 3.);
+        assert_eq!(quantile(&x, 1.), 5.);
+        assert_eq!(quantile(&x, 0.125), 1.5);
+        assert!(quantile(&[], 0.5).is_nan());
+    }
+
+    #[test]
+    fn test_cmp_nan_last() {
+        let mut x = vec![2., -f64::NAN, f64::INFINITY, -1., f64::NAN];
+        x.sort_by(cmp_nan_last);
+        assert_eq!(&x[..3], &[-1., 2., f64::INFINITY]);
+        assert!(x[3].is_nan() && x[4].is_nan());
+    }
 }
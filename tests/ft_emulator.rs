@@ -0,0 +1,139 @@
+#![cfg(unix)]
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use dragonfly::{
+    calibration::{
+        emulator::{EmulatorConfig, FTEmulator},
+        FTAction, FTCommand, FTCommandResult, FilterTilter, TravelLimits,
+    },
+    error::{Error, Result},
+};
+
+fn run(port: &str, command: FTCommand, value: f64) -> Result<(FTCommandResult, f64)> {
+    FTAction {
+        command,
+        value,
+        portname: port,
+        simulation: None,
+        limits: TravelLimits::default(),
+        verbose: false,
+    }
+    .run()
+}
+
+#[test]
+fn test_commands_round_trip() {
+    let emulator = FTEmulator::new(EmulatorConfig::default()).unwrap().spawn();
+    let port = emulator.port_name().to_owned();
+
+    let (tag, raw) = run(&port, FTCommand::GETRAW, 0.).unwrap();
+    assert_eq!(tag, FTCommandResult::R);
+    assert_eq!(raw, 171.);
+
+    let (_, raw) = run(&port, FTCommand::SETRAW, 182.25).unwrap();
+    assert_eq!(raw, 182.25);
+
+    let (tag, zero) = run(&port, FTCommand::ZERO, 0.).unwrap();
+    assert_eq!(tag, FTCommandResult::Z);
+    assert_eq!(zero, 182.25);
+
+    let (tag, angle) = run(&port, FTCommand::SET, -3.5).unwrap();
+    assert_eq!(tag, FTCommandResult::A);
+    assert_eq!(angle, -3.5);
+
+    let (_, angle) = run(&port, FTCommand::GET, 0.).unwrap();
+    assert_eq!(angle, -3.5);
+
+    let (_, zero) = run(&port, FTCommand::SETZERO, 180.).unwrap();
+    assert_eq!(zero, 180.);
+    let (_, zero) = run(&port, FTCommand::GETZERO, 0.).unwrap();
+    assert_eq!(zero, 180.);
+
+    let state = emulator.state();
+    assert_eq!(state.rawangle, 178.75);
+    assert_eq!(state.zeropoint, 180.);
+
+    emulator.stop().unwrap();
+}
+
+#[test]
+fn test_latency() {
+    let emulator = FTEmulator::new(EmulatorConfig {
+        latency: Duration::from_millis(300),
+        ..Default::default()
+    })
+    .unwrap()
+    .spawn();
+
+    let start = Instant::now();
+    let (_, raw) = run(emulator.port_name(), FTCommand::GETRAW, 0.).unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(raw, 171.);
+    assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+}
+
+#[test]
+fn test_garbled_reply_is_an_error() {
+    let emulator = FTEmulator::new(EmulatorConfig {
+        garble_probability: 1.,
+        ..Default::default()
+    })
+    .unwrap()
+    .spawn();
+
+    assert!(run(emulator.port_name(), FTCommand::GETRAW, 0.).is_err());
+}
+
+#[test]
+fn test_disconnect_is_an_error() {
+    let emulator = FTEmulator::new(EmulatorConfig {
+        disconnect_after: Some(1),
+        ..Default::default()
+    })
+    .unwrap()
+    .spawn();
+    let port = emulator.port_name().to_owned();
+
+    assert!(run(&port, FTCommand::GETRAW, 0.).is_ok());
+    assert!(run(&port, FTCommand::GETRAW, 0.).is_err());
+}
+
+#[test]
+fn test_move_to_waits_for_slew() {
+    let emulator = FTEmulator::new(EmulatorConfig {
+        zeropoint: 180.,
+        slew_rate: Some(10.),
+        ..Default::default()
+    })
+    .unwrap()
+    .spawn();
+
+    let mut tilter =
+        FilterTilter::serial(emulator.port_name(), false).poll_interval(Duration::from_millis(100));
+
+    let result = tilter.move_to(176., 0.05, Duration::from_secs(5)).unwrap();
+    assert!((result.raw_angle - 176.).abs() <= 0.05);
+    assert!((result.angle + 4.).abs() <= 0.05);
+
+    match tilter.move_to(200., 0.05, Duration::from_millis(500)) {
+        Err(Error::NotSettled {
+            target,
+            reported,
+            stop_error,
+        }) => {
+            assert_eq!(target, 200.);
+            assert!(reported < 200.);
+            assert_eq!(stop_error, None);
+        }
+        other => panic!("expected the move to time out, got {:?}", other),
+    }
+
+    // The emergency stop after the timeout leaves the filter where it was.
+    let stopped = tilter.get_raw_angle().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!((tilter.get_raw_angle().unwrap() - stopped).abs() < 0.01);
+}